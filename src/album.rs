@@ -0,0 +1,149 @@
+// SPDX-License-Identifier: GPL-3.0-only
+// 🄯 2026, Alexey Parfenov <zxed@alkatrazstudio.net>
+
+use crate::convert::Item;
+use crate::meta::FileMeta;
+use std::collections::HashMap;
+use std::path::Path;
+
+pub struct Album {
+    pub items: Vec<usize>,
+    pub is_compilation: bool,
+}
+
+pub struct Albums {
+    pub list: Vec<Album>,
+    item_albums: Vec<Option<usize>>,
+}
+
+fn album_key(item: &Item, meta: &FileMeta) -> String {
+    // all tracks of a cue sheet belong to the same album,
+    // otherwise the tracks are grouped by their directory
+    let source = if let Some(cue) = &item.cue {
+        cue.cue_filename.as_str()
+    } else {
+        Path::new(&item.filename)
+            .parent()
+            .and_then(|p| p.to_str())
+            .unwrap_or_default()
+    };
+    return format!("{}\n{}", source, meta.tags.album.to_lowercase());
+}
+
+fn normalize_artist(s: &str) -> String {
+    return s.trim().to_lowercase();
+}
+
+fn detect_compilation(metas: &[&FileMeta], various_artists_name: &str) -> bool {
+    if metas.iter().any(|meta| meta.compilation) {
+        return true;
+    }
+
+    let album_artist = normalize_artist(&metas[0].album_artist);
+    if !album_artist.is_empty()
+        && metas
+            .iter()
+            .all(|meta| normalize_artist(&meta.album_artist) == album_artist)
+    {
+        return album_artist == normalize_artist(various_artists_name);
+    }
+
+    let mut artists = metas
+        .iter()
+        .map(|meta| normalize_artist(&meta.track_artist))
+        .filter(|artist| !artist.is_empty())
+        .collect::<Vec<String>>();
+    artists.sort_unstable();
+    artists.dedup();
+    return artists.len() > 1;
+}
+
+impl Albums {
+    pub fn new(
+        items: &[Item],
+        metas: &[Result<FileMeta, String>],
+        various_artists_name: &str,
+    ) -> Self {
+        let mut list: Vec<Album> = Vec::new();
+        let mut item_albums = vec![None; items.len()];
+        let mut keys = HashMap::new();
+
+        for (i, (item, meta)) in items.iter().zip(metas).enumerate() {
+            if let Ok(meta) = meta {
+                let key = album_key(item, meta);
+                let album_index = *keys.entry(key).or_insert_with(|| {
+                    list.push(Album {
+                        items: Vec::new(),
+                        is_compilation: false,
+                    });
+                    list.len() - 1
+                });
+                list[album_index].items.push(i);
+                item_albums[i] = Some(album_index);
+            }
+        }
+
+        for album in &mut list {
+            let album_metas = album
+                .items
+                .iter()
+                .filter_map(|&i| metas[i].as_ref().ok())
+                .collect::<Vec<&FileMeta>>();
+            album.is_compilation = detect_compilation(&album_metas, various_artists_name);
+        }
+
+        return Self { list, item_albums };
+    }
+
+    // Keeps only the items marked in `keep`, reindexing the albums.
+    // The albums stay as they were detected for all their items.
+    pub fn retain(self, keep: &[bool]) -> Self {
+        let mut new_indexes = Vec::with_capacity(keep.len());
+        let mut n = 0;
+        for &k in keep {
+            new_indexes.push(k.then_some(n));
+            n += usize::from(k);
+        }
+
+        let mut list = Vec::new();
+        let mut item_albums = vec![None; n];
+        for album in self.list {
+            let items = album
+                .items
+                .iter()
+                .filter_map(|&i| new_indexes[i])
+                .collect::<Vec<_>>();
+            if items.is_empty() {
+                continue;
+            }
+            for &i in &items {
+                item_albums[i] = Some(list.len());
+            }
+            list.push(Album {
+                items,
+                is_compilation: album.is_compilation,
+            });
+        }
+
+        return Self { list, item_albums };
+    }
+
+    pub fn for_item(&self, index: usize) -> Option<&Album> {
+        return self.item_albums[index].map(|i| &self.list[i]);
+    }
+
+    pub fn apply_tags(&self, metas: &mut [Result<FileMeta, String>], various_artists_name: &str) {
+        for (i, meta) in metas.iter_mut().enumerate() {
+            if let Ok(meta) = meta
+                && let Some(album) = self.for_item(i)
+            {
+                if album.is_compilation {
+                    meta.tags.is_compilation = "1".to_string();
+                    meta.tags.album_artist_or_va = various_artists_name.to_string();
+                } else {
+                    meta.tags.album_artist_or_va.clone_from(&meta.tags.artist);
+                }
+            }
+        }
+    }
+}
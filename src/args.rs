@@ -1,10 +1,18 @@
 // SPDX-License-Identifier: GPL-3.0-only
 // 🄯 2021, Alexey Parfenov <zxed@alkatrazstudio.net>
 
-use crate::formats::Format;
+use crate::companions::CompanionMode;
+use crate::cue::{CueOptions, PregapPolicy};
+use crate::files::WalkOptions;
+use crate::filters::{PathFilters, PathPattern, TagFilter, path_pattern, tag_filter};
+use crate::formats::{Format, LossyPolicy, SameFormatPolicy};
+use crate::loudness::{LoudnessNormMode, LoudnessTarget};
+use crate::replaygain::ReplayGainMode;
+use crate::resample::LimitMode;
 use clap::builder::{NonEmptyStringValueParser, RangedU64ValueParser};
 use clap::error::ErrorKind;
 use clap::{Arg, ArgAction, Command};
+use encoding_rs::Encoding;
 use std::error::Error;
 use std::io::BufWriter;
 use std::num::NonZeroUsize;
@@ -17,10 +25,21 @@ mod built {
 
 pub struct AppArgs {
     pub input_dirs: Vec<String>,
+    pub input_lists: Vec<String>,
     pub output_dir: String,
     pub filename_template: String,
     pub dry_run: bool,
+    pub lint: bool,
+    pub merge_template: Option<String>,
+    pub playlist_templates: Vec<String>,
+    pub rewrite_playlists: bool,
+    pub companions: Vec<PathPattern>,
+    pub companion_mode: CompanionMode,
+    pub companion_follow_track: bool,
     pub input_exts: Vec<String>,
+    pub path_filters: PathFilters,
+    pub tag_filters: Vec<TagFilter>,
+    pub walk: WalkOptions,
     pub output_ext: String,
     pub output_ext_type: Format,
     pub overwrite: bool,
@@ -36,7 +55,23 @@ pub struct AppArgs {
     pub cover_name_from_dirname: bool,
     pub cover_name_from_filename: bool,
     pub cover_exts: Vec<String>,
+    pub cover_file: Option<String>,
+    pub pic_cache_dir: Option<String>,
+    pub pic_cache_size: u64,
     pub min_track_number_digits: u8,
+    pub various_artists_name: String,
+    pub values_separator: String,
+    pub replaygain: ReplayGainMode,
+    pub loudness_norm: LoudnessNormMode,
+    pub loudness_target: LoudnessTarget,
+    pub sample_rate: Option<u32>,
+    pub sample_rate_mode: LimitMode,
+    pub channels: Option<u32>,
+    pub channels_mode: LimitMode,
+    pub dither: Option<String>,
+    pub same_format: SameFormatPolicy,
+    pub lossy_source: LossyPolicy,
+    pub cue: CueOptions,
 }
 
 fn opt_string_vec(opt: Option<&String>) -> Vec<String> {
@@ -50,6 +85,32 @@ fn opt_string_vec(opt: Option<&String>) -> Vec<String> {
     return parts;
 }
 
+fn limit_mode(opt: Option<&String>) -> LimitMode {
+    return match opt.map(String::as_str) {
+        Some("exact") => LimitMode::Exact,
+        _ => LimitMode::Cap,
+    };
+}
+
+fn cue_encoding(s: &str) -> Result<Option<&'static Encoding>, String> {
+    if s == "auto" {
+        return Ok(None);
+    }
+    return Encoding::for_label(s.as_bytes())
+        .map(Some)
+        .ok_or_else(|| format!("unknown encoding: {s}"));
+}
+
+fn ranged_f64(min: f64, max: f64) -> impl Fn(&str) -> Result<f64, String> + Clone {
+    return move |s: &str| {
+        let val = s.parse::<f64>().map_err(|e| e.to_string())?;
+        if val < min || val > max {
+            return Err(format!("{val} is not in {min}..{max}"));
+        }
+        return Ok(val);
+    };
+}
+
 pub fn parse_cli_args() -> Result<Option<AppArgs>, Box<dyn Error>> {
     let v = "v".to_owned() + built::PKG_VERSION;
     let git_hash = built::GIT_COMMIT_HASH.unwrap_or_default();
@@ -105,20 +166,43 @@ pub fn parse_cli_args() -> Result<Option<AppArgs>, Box<dyn Error>> {
                 Directory to search for audio files.\n\
                 This directory will be searched recursively.\n\
                 Only files with INPUT_EXT extensions will be considered.\n\
+                Cue-sheets (*.cue) are also read, and the files they reference are split into tracks.\n\
+                The referenced files are matched case-insensitively,\n\
+                and any of INPUT_EXT extensions may replace the referenced extension.\n\
+                A cue-sheet embedded into the tags of an audio file is used if there's no cue-sheet file for it.\n\
                 This option can be specified multiple times.")
-            .required(true)
+            .required_unless_present("INPUT_LIST")
             .action(ArgAction::Append)
             .number_of_values(1)
             .value_parser(NonEmptyStringValueParser::new())
             .display_order(0))
 
+        .arg(Arg::new("INPUT_LIST")
+            .long("input-list")
+            .long_help("\
+                File with a list of audio files and cue-sheets to convert.\n\
+                *.m3u and *.m3u8 files are read as playlists,\n\
+                and the relative paths inside them are resolved against the location of the playlist.\n\
+                Any other file is a list of paths separated either by NUL characters or by newlines,\n\
+                and the relative paths inside it are resolved against the current directory.\n\
+                - means reading such list from stdin, e.g. find ... -print0 | musbconv --input-list - ...\n\
+                The listed files are converted regardless of INPUT_EXT.\n\
+                The cue-sheets next to the listed audio files are also read.\n\
+                This option can be specified multiple times and can be combined with INPUT_DIR.")
+            .action(ArgAction::Append)
+            .number_of_values(1)
+            .value_parser(NonEmptyStringValueParser::new())
+            .value_name("FILE")
+            .display_order(0))
+
         .arg(Arg::new("OUTPUT_DIR")
             .long("output-dir")
             .long_help("\
                 Base directory for writing the converted files.\n\
                 All converted output files will be located under this directory.\n\
                 The actual location of the file depends on a FILENAME_TEMPLATE.")
-            .required(true)
+            .required_unless_present("LINT")
+            .required_if_eq("LINT", "n")
             .value_parser(NonEmptyStringValueParser::new())
             .display_order(1))
 
@@ -131,7 +215,8 @@ pub fn parse_cli_args() -> Result<Option<AppArgs>, Box<dyn Error>> {
                 \x20 {{title}} - track title (if empty: defaults to {{file_base}})\n\
                 \x20 {{album}} - album name (if empty: defaults to {{dir_name}})\n\
                 \x20 {{artist}} - artist (if empty: defaults to {{author}} or {{performer}})\n\
-                \x20 {{catalog_number}} - catalog number\n\
+                \x20 {{catalog_number}} - catalog number (can be taken from the track itself or its cue-sheet)\n\
+                \x20 {{isrc}} - ISRC (can be taken from the track itself or its cue-sheet)\n\
                 \x20 {{author}} - track author (if empty: defaults to {{artist}} or {{performer}})\n\
                 \x20 {{comment}} - comment\n\
                 \x20 {{composer}} - composer (if empty: defaults to {{songwriter}}, {{lyricist}} or {{artist}})\n\
@@ -152,10 +237,19 @@ pub fn parse_cli_args() -> Result<Option<AppArgs>, Box<dyn Error>> {
                 \x20 {{dir_name}} - directory name (without parent directories)\n\
                 \x20 {{file_base}} - input file name without the extension\n\
                 \x20 {{file_ext}} - file extension without a leading dot\n\
+                \x20 {{is_compilation}} - \"1\" if the album is a compilation (see --various-artists-name)\n\
+                \x20 {{album_artist_or_va}} - VARIOUS_ARTISTS_NAME for compilations, otherwise {{artist}}\n\
                 All values in these placeholders will be present, but some of them may be empty strings.\n\
                 The values will be sanitized for a safe usage in a file paths\n\
-                and also directory separators will be removed.")
-            .required(true)
+                and also directory separators will be removed.\n\
+                The following placeholders may contain multiple values:\n\
+                {{artist}}, {{author}}, {{composer}}, {{lyricist}}, {{songwriter}},\n\
+                {{genre}}, {{label}}, {{performer}}, {{publisher}}.\n\
+                By default, multiple values are joined with VALUES_SEPARATOR,\n\
+                but it's also possible to use these helpers:\n\
+                \x20 {{first artist}} - only the first value\n\
+                \x20 {{join artist \" & \"}} - all values joined with the specified separator")
+            .required_unless_present_any(["LINT", "MERGE_TEMPLATE"])
             .value_parser(NonEmptyStringValueParser::new())
             .display_order(2))
 
@@ -176,6 +270,96 @@ pub fn parse_cli_args() -> Result<Option<AppArgs>, Box<dyn Error>> {
             .value_name("y|n")
             .default_value("n"))
 
+        .arg(Arg::new("LINT")
+            .long("lint")
+            .long_help("\
+                Check the cue-sheets inside INPUT_DIR instead of converting anything.\n\
+                Reports parse errors, missing referenced files, non-monotonic indices,\n\
+                tracks past the end of the audio, missing titles and encoding problems.\n\
+                OUTPUT_DIR and FILENAME_TEMPLATE are not required in this mode.")
+            .value_parser(["y", "n"])
+            .value_name("y|n"))
+
+        .arg(Arg::new("MERGE_TEMPLATE")
+            .long("merge-template")
+            .long_help("\
+                Merge the tracks of every album into a single FLAC image instead of converting them.\n\
+                A cue-sheet with the titles, performers and positions of the tracks\n\
+                is written next to the image.\n\
+                The template is rendered with the tags of the first track of the album\n\
+                and supports the same placeholders as FILENAME_TEMPLATE.\n\
+                The tracks are merged in the order of their filenames.\n\
+                The loudness normalization, resampling and cover art are not applied to the image.\n\
+                FILENAME_TEMPLATE is not required in this mode.")
+            .value_parser(NonEmptyStringValueParser::new()))
+
+        .arg(Arg::new("PLAYLIST_TEMPLATE")
+            .long("playlist-template")
+            .long_help("\
+                Template for the filename of an M3U8 playlist inside OUTPUT_DIR (without the extension).\n\
+                The template is rendered with the tags of every converted track,\n\
+                supports the same placeholders as FILENAME_TEMPLATE,\n\
+                and the track is added to the resulting playlist.\n\
+                The entries of a playlist are relative to its location\n\
+                and are ordered by their directory, disc number and track number.\n\
+                Examples:\n\
+                \x20 \"{{album_artist_or_va}}/{{album}}/{{album}}\" - a playlist per album\n\
+                \x20 \"{{first artist}}\" - a playlist per artist\n\
+                \x20 \"All\" - a single playlist with all tracks\n\
+                This option can be specified multiple times.")
+            .action(ArgAction::Append)
+            .number_of_values(1)
+            .conflicts_with("MERGE_TEMPLATE")
+            .value_parser(NonEmptyStringValueParser::new())
+            .value_name("TEMPLATE"))
+
+        .arg(Arg::new("REWRITE_PLAYLISTS")
+            .long("rewrite-playlists")
+            .long_help("\
+                Rewrite the playlists (*.m3u, *.m3u8) found inside INPUT_DIR to reference the converted files.\n\
+                Every playlist is written as M3U8 into the same location relative to OUTPUT_DIR.\n\
+                An entry that references a cue-sheet or its audio file is replaced with all tracks of the cue-sheet.\n\
+                The entries that do not correspond to any converted file are reported and omitted.")
+            .value_parser(["y", "n"])
+            .value_name("y|n")
+            .default_value("n")
+            .conflicts_with("MERGE_TEMPLATE"))
+
+        .arg(Arg::new("COMPANION")
+            .long("companion")
+            .long_help("\
+                Copy the non-audio files that match this pattern into the output directory of their album,\n\
+                e.g. booklet.pdf, *.log, *.lrc or Scans/**.\n\
+                The pattern is matched against the file path relative to the source directory of the album,\n\
+                and has the same format as in --include.\n\
+                The audio files (see --input-ext), cue sheets and playlists are never copied.\n\
+                The output directory of the album is the directory of its first converted track.\n\
+                This option can be specified multiple times.")
+            .action(ArgAction::Append)
+            .number_of_values(1)
+            .value_parser(path_pattern)
+            .value_name("PATTERN"))
+
+        .arg(Arg::new("COMPANION_MODE")
+            .long("companion-mode")
+            .long_help("\
+                How to put the companion files (see --companion) into the output directory.\n\
+                copy - copy the files.\n\
+                symlink - create symbolic links to the source files.")
+            .value_parser(["copy", "symlink"])
+            .value_name("copy|symlink")
+            .default_value("copy"))
+
+        .arg(Arg::new("COMPANION_FOLLOW_TRACK")
+            .long("companion-follow-track")
+            .long_help("\
+                Rename the companion files named after an audio file (e.g. 01.lrc for 01.flac)\n\
+                to follow the name of the converted track (e.g. \"01. Title.lrc\" for \"01. Title.mp3\").\n\
+                Such files are put next to the converted track.")
+            .value_parser(["y", "n"])
+            .value_name("y|n")
+            .default_value("y"))
+
         .arg(Arg::new("INPUT_EXT")
             .long("input-ext")
             .long_help( "\
@@ -189,6 +373,82 @@ pub fn parse_cli_args() -> Result<Option<AppArgs>, Box<dyn Error>> {
             .value_parser(NonEmptyStringValueParser::new())
             .value_name("ext1,ext2,..."))
 
+        .arg(Arg::new("FOLLOW_SYMLINKS")
+            .long("follow-symlinks")
+            .long_help("\
+                Follow symbolic links to files and directories inside INPUT_DIR.\n\
+                A directory or a file that is reached more than once\n\
+                (e.g. via a symlink loop) is only processed the first time.")
+            .value_parser(["y", "n"])
+            .value_name("y|n")
+            .default_value("n"))
+
+        .arg(Arg::new("SKIP_HIDDEN")
+            .long("skip-hidden")
+            .long_help("\
+                Skip the files and directories whose names start with a dot,\n\
+                e.g. .Trash-1000 or .cache.")
+            .value_parser(["y", "n"])
+            .value_name("y|n")
+            .default_value("n"))
+
+        .arg(Arg::new("MAX_DEPTH")
+            .long("max-depth")
+            .long_help("\
+                Maximum number of the nested directory levels to descend into inside INPUT_DIR.\n\
+                0 - only the files directly inside INPUT_DIR.\n\
+                If not specified then the depth is unlimited.")
+            .value_parser(RangedU64ValueParser::<usize>::new())
+            .value_name("DEPTH"))
+
+        .arg(Arg::new("INCLUDE")
+            .long("include")
+            .long_help("\
+                Only process the files that match this pattern.\n\
+                The pattern is matched against the file path relative to INPUT_DIR,\n\
+                with / as a directory separator.\n\
+                The pattern is a case-insensitive glob, e.g. **/Classical/**.\n\
+                A glob without / is matched against the file name only, e.g. *.flac.\n\
+                The pattern is a regular expression if it starts with re:, e.g. re:(?i)/live/.\n\
+                Both audio files and cue-sheets are matched.\n\
+                This option can be specified multiple times,\n\
+                and a file is processed if it matches any of the patterns.")
+            .action(ArgAction::Append)
+            .number_of_values(1)
+            .value_parser(path_pattern)
+            .value_name("PATTERN"))
+
+        .arg(Arg::new("EXCLUDE")
+            .long("exclude")
+            .long_help("\
+                Skip the files that match this pattern, e.g. **/_incoming/** or *.bak.flac.\n\
+                The pattern has the same format as in --include.\n\
+                Exclusion takes precedence over inclusion.\n\
+                This option can be specified multiple times.")
+            .action(ArgAction::Append)
+            .number_of_values(1)
+            .value_parser(path_pattern)
+            .value_name("PATTERN"))
+
+        .arg(Arg::new("TAG_FILTER")
+            .long("tag-filter")
+            .long_help("\
+                Only process the tracks with matching tags.\n\
+                NAME=VALUE - the tag is equal to the value (case-insensitive).\n\
+                NAME!=VALUE - the tag is not equal to the value (case-insensitive).\n\
+                NAME~REGEX - the tag matches the regular expression.\n\
+                NAME is one of the placeholder names of FILENAME_TEMPLATE, e.g. genre=Classical.\n\
+                A tag with multiple values matches if any of its values matches.\n\
+                The tags are checked after probing the files, so the cue-sheet values are also used.\n\
+                The albums are detected before filtering, so {{is_compilation}} and {{album_artist_or_va}}\n\
+                are based on all tracks of the album.\n\
+                This option can be specified multiple times,\n\
+                and a track is processed if it matches all of the filters.")
+            .action(ArgAction::Append)
+            .number_of_values(1)
+            .value_parser(tag_filter)
+            .value_name("FILTER"))
+
         .arg(Arg::new("OUTPUT_EXT")
             .long("output-ext")
             .long_help(output_ext_help)
@@ -286,6 +546,38 @@ pub fn parse_cli_args() -> Result<Option<AppArgs>, Box<dyn Error>> {
             .value_name("FILENAME")
             .default_value("jpeg,jpg,png,gif"))
 
+        .arg(Arg::new("COVER_FILE")
+            .long("cover-file")
+            .long_help("\
+                Also write the cover art as a separate JPEG file with this name,\n\
+                e.g. folder.jpg or cover.jpg, into every output directory with converted tracks.\n\
+                The cover art is resized according to MAX_PIC_WIDTH, MAX_PIC_HEIGHT and PIC_QUALITY.\n\
+                The embedded cover art is used if USE_EMBED_PIC is set, otherwise the cover art file is searched.\n\
+                If the tracks of a directory have different cover art then only the first one is written,\n\
+                and the other ones are reported as errors.")
+            .value_parser(NonEmptyStringValueParser::new())
+            .value_name("FILENAME"))
+
+        .arg(Arg::new("PIC_CACHE_DIR")
+            .long("pic-cache-dir")
+            .long_help("\
+                Directory to keep the converted cover art images between runs.\n\
+                The images are looked up by the content of the cover art file\n\
+                and by MAX_PIC_WIDTH, MAX_PIC_HEIGHT and PIC_QUALITY.\n\
+                The embedded cover art is not cached.\n\
+                The directory will be created if it does not exist.")
+            .value_parser(NonEmptyStringValueParser::new())
+            .value_name("DIR"))
+
+        .arg(Arg::new("PIC_CACHE_SIZE")
+            .long("pic-cache-size")
+            .long_help("\
+                Maximum size of PIC_CACHE_DIR in megabytes.\n\
+                The least recently used images are removed when the size is exceeded.")
+            .value_name("MB")
+            .default_value("100")
+            .value_parser(RangedU64ValueParser::<u64>::new().range(1..)))
+
         .arg(Arg::new("MIN_TRACK_NUMBER_DIGITS")
             .long("min-track-number-digits")
             .long_help("\
@@ -304,6 +596,218 @@ pub fn parse_cli_args() -> Result<Option<AppArgs>, Box<dyn Error>> {
             .default_value("2")
             .value_parser(RangedU64ValueParser::<u8>::new().range(1..10)))
 
+        .arg(Arg::new("VARIOUS_ARTISTS_NAME")
+            .long("various-artists-name")
+            .long_help("\
+                Album artist for compilations.\n\
+                All tracks from the same cue-sheet, or from the same directory with the same album name,\n\
+                are considered to be a single album.\n\
+                An album is a compilation if any of its tracks has a \"compilation\" tag,\n\
+                or if its tracks have different artists and no common album artist.\n\
+                This name is used for {{album_artist_or_va}} placeholder in FILENAME_TEMPLATE\n\
+                and for the \"album_artist\" tag of the compilation tracks.")
+            .value_name("NAME")
+            .default_value("Various Artists")
+            .value_parser(NonEmptyStringValueParser::new()))
+
+        .arg(Arg::new("VALUES_SEPARATOR")
+            .long("values-separator")
+            .long_help("\
+                Separator for the tags with multiple values in FILENAME_TEMPLATE.\n\
+                Multiple values are read from the tags that are repeated in the source file,\n\
+                or from the tags that have values separated by \";\" or \" / \".\n\
+                The output files will have such values written as separate values:\n\
+                null-separated for ID3v2.4 and repeated fields for Vorbis comments.")
+            .value_name("SEPARATOR")
+            .default_value("; "))
+
+        .arg(Arg::new("REPLAYGAIN")
+            .long("replaygain")
+            .long_help("\
+                Calculate ReplayGain and write it to the output files.\n\
+                n - do not calculate ReplayGain, but preserve the existing values.\n\
+                track - calculate the track gain and peak, and remove the existing album values.\n\
+                album - calculate the track and album gain and peak.\n\
+                The loudness is measured with EBU R128 (ffmpeg \"loudnorm\" filter)\n\
+                against the ReplayGain 2.0 reference level of -18 LUFS.\n\
+                All tracks from the same cue-sheet, or from the same directory with the same album name,\n\
+                are considered to be a single album.\n\
+                The track values from a cue-sheet image are never preserved.")
+            .value_parser(["n", "track", "album"])
+            .value_name("n|track|album")
+            .default_value("n"))
+
+        .arg(Arg::new("LOUDNESS_NORM")
+            .long("loudness-norm")
+            .long_help("\
+                Normalize the loudness of the audio itself.\n\
+                n - do not normalize.\n\
+                track - normalize each track to the target loudness separately.\n\
+                album - apply the same gain to all tracks of an album,\n\
+                \x20       so the album as a whole matches the target loudness.\n\
+                The loudness of each track is measured by a separate ffmpeg pass (\"loudnorm\" filter),\n\
+                then the measured values are used when encoding the track.\n\
+                In \"track\" mode ffmpeg \"loudnorm\" filter is used for the second pass.\n\
+                In \"album\" mode ffmpeg \"volume\" filter is used for the second pass\n\
+                and the gain is limited so that the album does not exceed LOUDNESS_TRUE_PEAK.\n\
+                When combined with --replaygain, the ReplayGain values are calculated for the normalized audio.")
+            .value_parser(["n", "track", "album"])
+            .value_name("n|track|album")
+            .default_value("n"))
+
+        .arg(Arg::new("LOUDNESS_TARGET")
+            .long("loudness-target")
+            .long_help("\
+                Target integrated loudness for --loudness-norm in LUFS.\n\
+                Must be in range of -70 to -5.")
+            .value_name("LUFS")
+            .default_value("-16")
+            .allow_negative_numbers(true)
+            .value_parser(ranged_f64(-70.0, -5.0)))
+
+        .arg(Arg::new("LOUDNESS_TRUE_PEAK")
+            .long("loudness-true-peak")
+            .long_help("\
+                Maximum true peak for --loudness-norm in dBTP.\n\
+                Must be in range of -9 to 0.")
+            .value_name("DBTP")
+            .default_value("-1.5")
+            .allow_negative_numbers(true)
+            .value_parser(ranged_f64(-9.0, 0.0)))
+
+        .arg(Arg::new("LOUDNESS_RANGE")
+            .long("loudness-range")
+            .long_help("\
+                Target loudness range for --loudness-norm=track in LU.\n\
+                Must be in range of 1 to 50.")
+            .value_name("LU")
+            .default_value("11")
+            .value_parser(ranged_f64(1.0, 50.0)))
+
+        .arg(Arg::new("SAMPLE_RATE")
+            .long("sample-rate")
+            .long_help("\
+                Sample rate of the output files in Hz.\n\
+                If not specified then the sample rate of the source file is preserved\n\
+                (unless the output format does not support it).\n\
+                Must be in range of 8000-192000.")
+            .value_name("HZ")
+            .value_parser(RangedU64ValueParser::<u32>::new().range(8000..=192_000)))
+
+        .arg(Arg::new("SAMPLE_RATE_MODE")
+            .long("sample-rate-mode")
+            .long_help("\
+                How to apply --sample-rate.\n\
+                exact - always resample to SAMPLE_RATE.\n\
+                cap - only downsample the sources that have a higher sample rate than SAMPLE_RATE.")
+            .value_parser(["exact", "cap"])
+            .value_name("exact|cap")
+            .default_value("cap"))
+
+        .arg(Arg::new("CHANNELS")
+            .long("channels")
+            .long_help("\
+                Number of audio channels in the output files.\n\
+                For example, --channels=1 will downmix stereo audio to mono.\n\
+                If not specified then the number of channels of the source file is preserved.\n\
+                Must be in range of 1-8.")
+            .value_name("NUM")
+            .value_parser(RangedU64ValueParser::<u32>::new().range(1..=8)))
+
+        .arg(Arg::new("CHANNELS_MODE")
+            .long("channels-mode")
+            .long_help("\
+                How to apply --channels.\n\
+                exact - always convert to CHANNELS.\n\
+                cap - only downmix the sources that have more channels than CHANNELS.")
+            .value_parser(["exact", "cap"])
+            .value_name("exact|cap")
+            .default_value("cap"))
+
+        .arg(Arg::new("DITHER")
+            .long("dither")
+            .long_help("\
+                Dither method to use when resampling.\n\
+                Has no effect if the sample rate is not changed.\n\
+                If not specified then ffmpeg's default is used.\n\
+                See the \"dither_method\" option of ffmpeg \"aresample\" filter for the details.")
+            .value_parser([
+                "none", "rectangular", "triangular", "triangular_hp", "lipshitz",
+                "shibata", "low_shibata", "high_shibata", "f_weighted", "e_weighted", "modified_e_weighted"])
+            .value_name("METHOD"))
+
+        .arg(Arg::new("SAME_FORMAT")
+            .long("same-format")
+            .long_help("\
+                What to do with the source files that are already encoded with the output codec\n\
+                (e.g. MP3 files when OUTPUT_EXT is mp3).\n\
+                transcode - re-encode the audio as any other source file.\n\
+                copy - copy the audio stream without re-encoding,\n\
+                \x20      but still write the tags, rename the file via FILENAME_TEMPLATE and embed the cover art.\n\
+                copy-file - copy the file byte-for-byte without any changes (only the file name changes).\n\
+                \x20           The tracks of cue-sheets are processed as in \"copy\" mode.\n\
+                cap - re-encode the audio only if its bitrate is higher than SAME_FORMAT_MAX_BITRATE,\n\
+                \x20     otherwise process it as in \"copy\" mode.\n\
+                The audio is always re-encoded if it needs to be normalized, resampled or downmixed.")
+            .value_parser(["transcode", "copy", "copy-file", "cap"])
+            .value_name("transcode|copy|copy-file|cap")
+            .default_value("transcode"))
+
+        .arg(Arg::new("SAME_FORMAT_MAX_BITRATE")
+            .long("same-format-max-bitrate")
+            .long_help("\
+                Maximum bitrate in kbit/s for --same-format=cap.\n\
+                Must be in range of 8-2000.")
+            .value_name("KBPS")
+            .default_value("320")
+            .value_parser(RangedU64ValueParser::<u32>::new().range(8..=2000)))
+
+        .arg(Arg::new("LOSSY_SOURCE")
+            .long("lossy-source")
+            .long_help("\
+                What to do when a source file encoded with a lossy codec (MP3, AAC, Vorbis, Opus)\n\
+                needs to be transcoded, which degrades its quality even further.\n\
+                allow - transcode silently.\n\
+                warn - transcode, but print a warning.\n\
+                skip - do not process the file and list it as skipped at the end.\n\
+                error - do not process the file and report an error.\n\
+                Does not apply to the files that are copied as per SAME_FORMAT\n\
+                and to the tracks merged with --merge-template.")
+            .value_parser(["allow", "warn", "skip", "error"])
+            .value_name("allow|warn|skip|error")
+            .default_value("warn"))
+
+        .arg(Arg::new("CUE_ENCODING")
+            .long("cue-encoding")
+            .long_help("\
+                Character encoding of the cue-sheets, e.g. windows-1251 or shift_jis.\n\
+                auto - UTF-8 if the cue-sheet is valid UTF-8, otherwise guess the encoding from its contents.\n\
+                The byte order mark (BOM) of a cue-sheet always takes precedence over this option.")
+            .value_name("auto|ENCODING")
+            .default_value("auto")
+            .value_parser(cue_encoding))
+
+        .arg(Arg::new("CUE_PREGAP")
+            .long("cue-pregap")
+            .long_help("\
+                Where to put the pregap of a cue-sheet track (the audio between its INDEX 00 and INDEX 01).\n\
+                append - to the end of the previous track.\n\
+                prepend - to the beginning of the track itself.\n\
+                discard - do not include the pregap in any track.")
+            .value_parser(["append", "prepend", "discard"])
+            .value_name("append|prepend|discard")
+            .default_value("append"))
+
+        .arg(Arg::new("CUE_HTOA")
+            .long("cue-htoa")
+            .long_help("\
+                Extract the hidden track before the first track of a cue-sheet (HTOA) as track 0.\n\
+                Otherwise, the hidden track is processed according to CUE_PREGAP,\n\
+                but it's never appended to any other track.")
+            .value_parser(["y", "n"])
+            .value_name("y|n")
+            .default_value("n"))
+
         .arg(Arg::new("FFMPEG_BIN")
             .long("ffmpeg-bin")
             .long_help("\
@@ -377,15 +881,114 @@ pub fn parse_cli_args() -> Result<Option<AppArgs>, Box<dyn Error>> {
                 _ => return Err(format!("Unsupported extension: {output_ext}").into()),
             };
 
+            let replaygain = match matches.get_one::<String>("REPLAYGAIN").unwrap().as_str() {
+                "track" => ReplayGainMode::Track,
+                "album" => ReplayGainMode::Album,
+                _ => ReplayGainMode::Off,
+            };
+
+            let loudness_norm = match matches.get_one::<String>("LOUDNESS_NORM").unwrap().as_str() {
+                "track" => LoudnessNormMode::Track,
+                "album" => LoudnessNormMode::Album,
+                _ => LoudnessNormMode::Off,
+            };
+
+            let same_format = match matches.get_one::<String>("SAME_FORMAT").unwrap().as_str() {
+                "copy" => SameFormatPolicy::CopyStream,
+                "copy-file" => SameFormatPolicy::CopyFile,
+                "cap" => SameFormatPolicy::BitrateCap(
+                    *matches.get_one::<u32>("SAME_FORMAT_MAX_BITRATE").unwrap(),
+                ),
+                _ => SameFormatPolicy::Transcode,
+            };
+
+            let lossy_source = match matches.get_one::<String>("LOSSY_SOURCE").unwrap().as_str() {
+                "allow" => LossyPolicy::Allow,
+                "skip" => LossyPolicy::Skip,
+                "error" => LossyPolicy::Error,
+                _ => LossyPolicy::Warn,
+            };
+
             return Ok(Some(AppArgs {
-                input_dirs: matches.get_many("INPUT_DIR").unwrap().cloned().collect(),
-                output_dir: matches.get_one::<String>("OUTPUT_DIR").unwrap().clone(),
+                input_dirs: matches
+                    .get_many("INPUT_DIR")
+                    .unwrap_or_default()
+                    .cloned()
+                    .collect(),
+                input_lists: matches
+                    .get_many("INPUT_LIST")
+                    .unwrap_or_default()
+                    .cloned()
+                    .collect(),
+                output_dir: matches
+                    .get_one::<String>("OUTPUT_DIR")
+                    .cloned()
+                    .unwrap_or_default(),
                 filename_template: matches
                     .get_one::<String>("FILENAME_TEMPLATE")
-                    .unwrap()
-                    .clone(),
+                    .cloned()
+                    .unwrap_or_default(),
                 dry_run: matches.get_one::<String>("DRY_RUN").unwrap().as_str() == "y",
+                lint: matches
+                    .get_one::<String>("LINT")
+                    .is_some_and(|lint| lint == "y"),
+                merge_template: matches.get_one::<String>("MERGE_TEMPLATE").cloned(),
+                playlist_templates: matches
+                    .get_many("PLAYLIST_TEMPLATE")
+                    .unwrap_or_default()
+                    .cloned()
+                    .collect(),
+                rewrite_playlists: matches
+                    .get_one::<String>("REWRITE_PLAYLISTS")
+                    .unwrap()
+                    .as_str()
+                    == "y",
+                companions: matches
+                    .get_many("COMPANION")
+                    .unwrap_or_default()
+                    .cloned()
+                    .collect(),
+                companion_mode: match matches
+                    .get_one::<String>("COMPANION_MODE")
+                    .unwrap()
+                    .as_str()
+                {
+                    "symlink" => CompanionMode::Symlink,
+                    _ => CompanionMode::Copy,
+                },
+                companion_follow_track: matches
+                    .get_one::<String>("COMPANION_FOLLOW_TRACK")
+                    .unwrap()
+                    .as_str()
+                    == "y"
+                    && !matches.contains_id("MERGE_TEMPLATE"),
                 input_exts,
+                path_filters: PathFilters {
+                    include: matches
+                        .get_many("INCLUDE")
+                        .unwrap_or_default()
+                        .cloned()
+                        .collect(),
+                    exclude: matches
+                        .get_many("EXCLUDE")
+                        .unwrap_or_default()
+                        .cloned()
+                        .collect(),
+                },
+                tag_filters: matches
+                    .get_many("TAG_FILTER")
+                    .unwrap_or_default()
+                    .cloned()
+                    .collect(),
+                walk: WalkOptions {
+                    follow_symlinks: matches
+                        .get_one::<String>("FOLLOW_SYMLINKS")
+                        .unwrap()
+                        .as_str()
+                        == "y",
+                    skip_hidden: matches.get_one::<String>("SKIP_HIDDEN").unwrap().as_str() == "y",
+                    max_depth: matches.get_one::<usize>("MAX_DEPTH").copied(),
+                },
                 output_ext: output_ext.clone(),
                 output_ext_type,
                 overwrite: matches.get_one::<String>("OVERWRITE").unwrap().as_str() == "y",
@@ -409,7 +1012,43 @@ pub fn parse_cli_args() -> Result<Option<AppArgs>, Box<dyn Error>> {
                     .as_str()
                     == "y",
                 cover_exts,
+                cover_file: matches.get_one::<String>("COVER_FILE").cloned(),
+                pic_cache_dir: matches.get_one::<String>("PIC_CACHE_DIR").cloned(),
+                pic_cache_size: *matches.get_one::<u64>("PIC_CACHE_SIZE").unwrap(),
                 min_track_number_digits: *matches.get_one::<u8>("MIN_TRACK_NUMBER_DIGITS").unwrap(),
+                various_artists_name: matches
+                    .get_one::<String>("VARIOUS_ARTISTS_NAME")
+                    .unwrap()
+                    .clone(),
+                values_separator: matches
+                    .get_one::<String>("VALUES_SEPARATOR")
+                    .unwrap()
+                    .clone(),
+                replaygain,
+                loudness_norm,
+                loudness_target: LoudnessTarget {
+                    integrated: *matches.get_one::<f64>("LOUDNESS_TARGET").unwrap(),
+                    true_peak: *matches.get_one::<f64>("LOUDNESS_TRUE_PEAK").unwrap(),
+                    lra: *matches.get_one::<f64>("LOUDNESS_RANGE").unwrap(),
+                },
+                sample_rate: matches.get_one::<u32>("SAMPLE_RATE").copied(),
+                sample_rate_mode: limit_mode(matches.get_one("SAMPLE_RATE_MODE")),
+                channels: matches.get_one::<u32>("CHANNELS").copied(),
+                channels_mode: limit_mode(matches.get_one("CHANNELS_MODE")),
+                dither: matches.get_one::<String>("DITHER").cloned(),
+                same_format,
+                lossy_source,
+                cue: CueOptions {
+                    encoding: *matches
+                        .get_one::<Option<&'static Encoding>>("CUE_ENCODING")
+                        .unwrap(),
+                    pregap: match matches.get_one::<String>("CUE_PREGAP").unwrap().as_str() {
+                        "prepend" => PregapPolicy::Prepend,
+                        "discard" => PregapPolicy::Discard,
+                        _ => PregapPolicy::Append,
+                    },
+                    htoa: matches.get_one::<String>("CUE_HTOA").unwrap().as_str() == "y",
+                },
             }));
         }
         Err(e) => match e.kind() {
@@ -0,0 +1,161 @@
+// SPDX-License-Identifier: GPL-3.0-only
+// 🄯 2026, Alexey Parfenov <zxed@alkatrazstudio.net>
+
+use crate::args::AppArgs;
+use crate::convert::{Item, OutputTrack};
+use crate::files::canonical;
+use crate::playlist::is_playlist;
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::path::{Path, PathBuf};
+
+#[derive(PartialEq, Eq)]
+pub enum CompanionMode {
+    Copy,
+    Symlink,
+}
+
+fn source_dir(item: &Item) -> Option<PathBuf> {
+    let filename = item
+        .cue
+        .as_ref()
+        .map_or(&item.filename, |cue| &cue.cue_filename);
+    return Some(canonical(Path::new(filename).parent()?));
+}
+
+// Returns the files relative to the directory.
+// The subdirectories with their own tracks belong to other albums and are not visited.
+fn collect_files(
+    dir: &Path,
+    rel_dir: &Path,
+    album_dirs: &BTreeMap<PathBuf, PathBuf>,
+    files: &mut Vec<PathBuf>,
+) {
+    let Ok(entries) = dir.read_dir() else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let Ok(file_type) = entry.file_type() else {
+            continue;
+        };
+        let path = entry.path();
+        let rel_path = rel_dir.join(entry.file_name());
+        if file_type.is_dir() {
+            if !album_dirs.contains_key(&path) {
+                collect_files(&path, &rel_path, album_dirs, files);
+            }
+        } else if file_type.is_file() {
+            files.push(rel_path);
+        }
+    }
+}
+
+// The audio files, cue sheets and playlists are handled on their own.
+fn is_source_file(path: &Path, input_exts: &[String]) -> bool {
+    let ext = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or_default()
+        .to_lowercase();
+    return ext == "cue" || is_playlist(path) || input_exts.contains(&ext);
+}
+
+fn copy_companion(src: &Path, dst: &Path, mode: &CompanionMode) -> Result<(), Box<dyn Error>> {
+    if let Some(dir) = dst.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    match mode {
+        CompanionMode::Copy => {
+            std::fs::copy(src, dst)?;
+        }
+        CompanionMode::Symlink => {
+            if dst.symlink_metadata().is_ok() {
+                std::fs::remove_file(dst)?;
+            }
+            #[cfg(unix)]
+            std::os::unix::fs::symlink(src, dst)?;
+            #[cfg(windows)]
+            std::os::windows::fs::symlink_file(src, dst)?;
+        }
+    }
+    return Ok(());
+}
+
+// Copies the files that match COMPANION patterns
+// from the source directory of every album into its output directory.
+// Returns the filenames of the copied files and the errors.
+pub fn copy_companions(
+    tracks: &[OutputTrack],
+    items: &[Item],
+    app_args: &AppArgs,
+) -> (Vec<String>, Vec<String>) {
+    let mut filenames = Vec::new();
+    let mut errs = Vec::new();
+    if app_args.companions.is_empty() {
+        return (filenames, errs);
+    }
+
+    // the output directory of the album is the directory of its first track
+    let mut album_dirs = BTreeMap::new();
+    // a companion file named after a source file follows the name of the converted track
+    let mut track_names = HashMap::new();
+    for track in tracks {
+        let item = &items[track.index];
+        let output_path = Path::new(track.filename);
+        if let Some(dir) = source_dir(item)
+            && let Some(output_dir) = output_path.parent()
+        {
+            album_dirs
+                .entry(dir)
+                .or_insert_with(|| output_dir.to_path_buf());
+        }
+        if item.cue.is_none() && app_args.companion_follow_track {
+            track_names.insert(
+                canonical(Path::new(&item.filename)).with_extension(""),
+                output_path.to_path_buf(),
+            );
+        }
+    }
+
+    for (dir, output_dir) in &album_dirs {
+        let mut files = Vec::new();
+        collect_files(dir, Path::new(""), &album_dirs, &mut files);
+        files.sort();
+
+        for rel_path in files {
+            if is_source_file(&rel_path, &app_args.input_exts) {
+                continue;
+            }
+            let rel_path_str = rel_path.to_string_lossy().replace('\\', "/");
+            if !app_args
+                .companions
+                .iter()
+                .any(|pattern| pattern.is_match(&rel_path_str))
+            {
+                continue;
+            }
+
+            let src = dir.join(&rel_path);
+            let dst = match (track_names.get(&src.with_extension("")), src.extension()) {
+                (Some(track_path), Some(ext)) => track_path.with_extension(ext),
+                _ => output_dir.join(&rel_path),
+            };
+            let dst_str = dst.to_string_lossy().to_string();
+
+            println!("[COMPANION] {} -> {}", src.display(), &dst_str);
+            if !app_args.overwrite && dst.exists() {
+                errs.push(format!("{}: file exists: {}", src.display(), &dst_str));
+                continue;
+            }
+            if !app_args.dry_run
+                && let Err(e) = copy_companion(&src, &dst, &app_args.companion_mode)
+            {
+                errs.push(format!("{}: {}", src.display(), e));
+                continue;
+            }
+            filenames.push(dst_str);
+        }
+    }
+
+    return (filenames, errs);
+}
@@ -2,22 +2,41 @@
 // 🄯 2021, Alexey Parfenov <zxed@alkatrazstudio.net>
 
 use crate::args::AppArgs;
-use crate::cue::CueInfo;
-use crate::formats::Format;
-use crate::meta::{MetaTags, extract_meta, prepare_filename_tags};
-use crate::pics::{PicsMap, ffmpeg_conv_pic_args, find_cover_in_dir};
-use handlebars::Handlebars;
+use crate::cue::{CueInfo, CueSegment};
+use crate::formats::{CopyMode, Format, LossyPolicy, SameFormatPolicy, is_lossy_codec};
+use crate::loudness::{LoudnessNormMode, norm_changes_sample_rate, norm_filters};
+use crate::meta::{
+    FileMeta, MetaTags, VALUES_SEPARATOR, join_values, prepare_filename_tags, tag_values,
+};
+use crate::pics::{PicsMap, ffmpeg_conv_pic_args, find_cover};
+use crate::replaygain::ReplayGainMode;
+use crate::resample::{channel_args, resample_filters};
+use crate::tags::{TagValues, write_multi_values, write_ogg_cover};
+use handlebars::{Handlebars, handlebars_helper};
 use path_dedot::ParseDot;
 use std::error::Error;
 use std::io::Write;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 pub struct Progs {
     pub ffmpeg_bin: String,
     pub ffprobe_bin: String,
 }
 
+pub enum ConvResult {
+    Filename(String),
+    Skipped(String),
+}
+
+// a successfully converted item
+pub struct OutputTrack<'a> {
+    pub index: usize,
+    pub filename: &'a str,
+    pub meta: &'a FileMeta,
+}
+
 pub struct Item {
     pub filename: String,
     pub basename: String,
@@ -31,13 +50,13 @@ impl Item {
         println!("[{}/{}:{}] {}", self.index + 1, self.total, cat, info);
     }
 
-    fn print_args(&self, cmd: &str, args: &[String]) {
+    pub fn print_args(&self, cmd: &str, args: &[String]) {
         let args = shell_words::join(args);
         self.print_info("CMD", &format!("{cmd} {args}"));
     }
 }
 
-fn sanitize_filename(filename: &str) -> Result<String, Box<dyn Error>> {
+pub fn sanitize_filename(filename: &str) -> Result<String, Box<dyn Error>> {
     let path = String::from("/..///") + filename;
     let path = Path::new(&path).parse_dot()?;
     let path = path.strip_prefix("/")?;
@@ -45,20 +64,27 @@ fn sanitize_filename(filename: &str) -> Result<String, Box<dyn Error>> {
     return Ok(path.to_string());
 }
 
-macro_rules! str_vec {
-    ($($x:expr),*) => (vec![$($x.to_string()),*]);
-}
-
-fn add_meta(args: &mut Vec<String>, val: &str, name: &str) {
+pub fn add_meta(args: &mut Vec<String>, val: &str, name: &str) {
     if !val.is_empty() {
+        let val = join_values(val, "; ");
         args.extend(str_vec!["-metadata", &format!("{name}={val}")]);
     }
 }
 
-fn render_template(template: &str, tags: &MetaTags) -> Result<String, Box<dyn Error>> {
+handlebars_helper!(first_value: |s: str| tag_values(s).into_iter().next().unwrap_or_default());
+handlebars_helper!(join_values_helper: |s: str, separator: str| join_values(s, separator));
+
+pub fn render_template(
+    template: &str,
+    tags: &MetaTags,
+    values_separator: &str,
+) -> Result<String, Box<dyn Error>> {
     let mut hb = Handlebars::new();
     hb.set_strict_mode(true);
-    hb.register_escape_fn(|s| s.into());
+    let values_separator = values_separator.to_string();
+    hb.register_escape_fn(move |s| s.replace(VALUES_SEPARATOR, &values_separator));
+    hb.register_helper("first", Box::new(first_value));
+    hb.register_helper("join", Box::new(join_values_helper));
 
     let result = hb.render_template(template, tags)?;
     return Ok(result);
@@ -66,7 +92,7 @@ fn render_template(template: &str, tags: &MetaTags) -> Result<String, Box<dyn Er
 
 pub fn validate_template(template: &str) -> Result<(), Box<dyn Error>> {
     let tags = MetaTags::default();
-    if let Err(e) = render_template(template, &tags) {
+    if let Err(e) = render_template(template, &tags, "") {
         return Err(format!("{e}").into());
     }
 
@@ -75,6 +101,7 @@ pub fn validate_template(template: &str) -> Result<(), Box<dyn Error>> {
         album: "1".to_string(),
         artist: "1".to_string(),
         catalog_number: "1".to_string(),
+        isrc: "1".to_string(),
         author: "1".to_string(),
         comment: "1".to_string(),
         composer: "1".to_string(),
@@ -95,34 +122,220 @@ pub fn validate_template(template: &str) -> Result<(), Box<dyn Error>> {
         dir_name: "1".to_string(),
         file_base: "1".to_string(),
         file_ext: "1".to_string(),
+        is_compilation: "1".to_string(),
+        album_artist_or_va: "1".to_string(),
     };
-    if let Err(e) = render_template(template, &tags) {
+    if let Err(e) = render_template(template, &tags, "") {
         return Err(format!("{e}").into());
     }
 
     return Ok(());
 }
 
+// how the source can be copied if the audio does not need to be processed
+fn source_copy_mode(item: &Item, meta: &FileMeta, app_args: &AppArgs) -> CopyMode {
+    if meta.codec != app_args.output_ext_type.codec_name() {
+        return CopyMode::None;
+    }
+
+    return match app_args.same_format {
+        SameFormatPolicy::Transcode => CopyMode::None,
+        SameFormatPolicy::CopyStream => CopyMode::Stream,
+        // a track of a cue-sheet image can't be copied as a whole file
+        SameFormatPolicy::CopyFile if item.cue.is_some() => CopyMode::Stream,
+        SameFormatPolicy::CopyFile => CopyMode::File,
+        SameFormatPolicy::BitrateCap(max_bitrate) => {
+            if meta
+                .bit_rate
+                .is_some_and(|bit_rate| bit_rate > u64::from(max_bitrate) * 1000)
+            {
+                CopyMode::None
+            } else {
+                CopyMode::Stream
+            }
+        }
+    };
+}
+
+fn copy_mode(item: &Item, meta: &FileMeta, app_args: &AppArgs, needs_filters: bool) -> CopyMode {
+    let copy_mode = source_copy_mode(item, meta, app_args);
+    if copy_mode != CopyMode::None && needs_filters {
+        item.print_info(
+            "INFO",
+            "the source is already in the output format, but the audio needs to be processed",
+        );
+        return CopyMode::None;
+    }
+
+    return copy_mode;
+}
+
+// All output formats are lossy,
+// so re-encoding a lossy source degrades its quality even further.
+// Decided before the loudness is measured, so that the skipped files are never decoded.
+// The loudness normalization is assumed to always process the audio.
+pub fn check_lossy_source(
+    item: &Item,
+    meta: &FileMeta,
+    app_args: &AppArgs,
+) -> Result<Option<String>, Box<dyn Error>> {
+    if app_args.lossy_source == LossyPolicy::Allow || !is_lossy_codec(&meta.codec) {
+        return Ok(None);
+    }
+
+    let needs_filters = app_args.loudness_norm != LoudnessNormMode::Off
+        || !resample_filters(meta, app_args, false).is_empty()
+        || !channel_args(meta, app_args).is_empty();
+    if !needs_filters && source_copy_mode(item, meta, app_args) != CopyMode::None {
+        return Ok(None);
+    }
+
+    let reason = format!(
+        "lossy-to-lossy transcoding ({} to {})",
+        meta.codec,
+        app_args.output_ext_type.codec_name()
+    );
+    return match app_args.lossy_source {
+        LossyPolicy::Error => Err(reason.into()),
+        LossyPolicy::Skip => Ok(Some(reason)),
+        _ => {
+            item.print_info("WARN", &reason);
+            Ok(None)
+        }
+    };
+}
+
+// A list of file parts for the ffmpeg concat demuxer.
+// The list is removed when dropped.
+pub struct ConcatList {
+    path: PathBuf,
+}
+
+impl Drop for ConcatList {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+fn concat_list_str(segments: &[CueSegment]) -> Result<String, Box<dyn Error>> {
+    let mut lines = str_vec!["ffconcat version 1.0"];
+    for segment in segments {
+        let path = Path::new(&segment.filename).canonicalize()?;
+        let path = path.to_str().ok_or("Can't convert path to string")?;
+        lines.push(format!("file '{}'", path.replace('\'', r"'\''")));
+        if segment.start_frame > 0 {
+            lines.push(format!("inpoint {:.6}", segment.start()));
+        }
+        if let Some(duration) = segment.duration() {
+            lines.push(format!("outpoint {:.6}", segment.start() + duration));
+        }
+    }
+    return Ok(lines.join("\n") + "\n");
+}
+
+fn write_concat_list(item: &Item, segments: &[CueSegment]) -> Result<ConcatList, Box<dyn Error>> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let path = std::env::temp_dir().join(format!(
+        "musbconv-{}-{}-{}.ffconcat",
+        std::process::id(),
+        item.index,
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    std::fs::write(&path, concat_list_str(segments)?)?;
+    return Ok(ConcatList { path });
+}
+
+// A sample-exact cut of a cue track that lies in a single file.
+// The file is seeked to the last whole second before the track,
+// which ffmpeg cuts at an exact sample, and the rest is cut by the filter.
+// Not possible without decoding the audio, i.e. when copying the stream.
+pub struct Trim {
+    seek: u64,
+    pub filter: String,
+}
+
+pub fn trim_filter(item: &Item, meta: &FileMeta) -> Option<Trim> {
+    let cue = item.cue.as_ref()?;
+    let [segment] = cue.segments.as_slice() else {
+        return None;
+    };
+    let sample_rate = meta.sample_rate?;
+
+    let start = segment.start_sample(sample_rate);
+    let seek = start / u64::from(sample_rate);
+    let seek_sample = seek * u64::from(sample_rate);
+    let end = segment
+        .end_sample(sample_rate)
+        .map(|end| format!(":end_sample={}", end - seek_sample))
+        .unwrap_or_default();
+    let filter = format!(
+        "atrim=start_sample={}{end},asetpts=PTS-STARTPTS",
+        start - seek_sample
+    );
+    return Some(Trim { seek, filter });
+}
+
+// The ffmpeg arguments to read the audio of the item.
+// A cue track is cut by seeking unless it's trimmed by trim_filter.
+// A cue track that spans several files is read via the concat demuxer,
+// and the returned list must be kept until ffmpeg finishes.
+pub fn input_args(
+    item: &Item,
+    dry_run: bool,
+    trim: Option<&Trim>,
+) -> Result<(Vec<String>, Option<ConcatList>), Box<dyn Error>> {
+    let Some(cue) = &item.cue else {
+        return Ok((str_vec!["-i", &item.filename], None));
+    };
+
+    if let [segment] = cue.segments.as_slice() {
+        let mut args = Vec::new();
+        if let Some(trim) = trim {
+            if trim.seek > 0 {
+                args.extend(str_vec!["-ss:a", trim.seek]);
+            }
+        } else {
+            args.extend(str_vec!["-ss:a", &format!("{:.3}", segment.start())]);
+            if let Some(duration) = segment.duration() {
+                args.extend(str_vec!["-t:a", &format!("{duration:.3}")]);
+            }
+        }
+        args.extend(str_vec!["-i", &segment.filename]);
+        return Ok((args, None));
+    }
+
+    if dry_run {
+        let args = str_vec!["-f", "concat", "-safe", "0", "-i", "<concat list>"];
+        return Ok((args, None));
+    }
+
+    let list = write_concat_list(item, &cue.segments)?;
+    let list_filename = list.path.to_str().ok_or("Can't convert path to string")?;
+    let args = str_vec!["-f", "concat", "-safe", "0", "-i", list_filename];
+    return Ok((args, Some(list)));
+}
+
 pub fn conv_item(
     item: &Item,
+    meta: &FileMeta,
     pics: &PicsMap,
     app_args: &AppArgs,
     progs: &Progs,
-) -> Result<String, Box<dyn Error>> {
+) -> Result<ConvResult, Box<dyn Error>> {
+    if let Some(reason) = &meta.skip_reason {
+        item.print_info("SKIP", reason);
+        return Ok(ConvResult::Skipped(reason.clone()));
+    }
+
     let input_filename = &item.filename;
     item.print_info("INFO", &format!("processing {}", &input_filename));
-    let canonical_dir = Path::new(input_filename)
-        .parent()
-        .ok_or_else(|| format!("no parent for {input_filename}"))?
-        .canonicalize()?;
-    let input_dir = canonical_dir
-        .to_str()
-        .ok_or("Can't get a string from the canonical path")?;
-
-    let meta = extract_meta(input_filename, item.cue.as_ref(), &progs.ffprobe_bin)?;
     let filename_tags = prepare_filename_tags(&meta.tags, app_args.min_track_number_digits);
 
-    let filename = render_template(&app_args.filename_template, &filename_tags)?;
+    let filename = render_template(
+        &app_args.filename_template,
+        &filename_tags,
+        &app_args.values_separator,
+    )?;
     let filename = filename + "." + &app_args.output_ext;
     let output_filename = sanitize_filename(&filename)?;
 
@@ -136,13 +349,51 @@ pub fn conv_item(
         return Err(format!("file exists: {output_path_str}").into());
     }
 
+    let mut filters = norm_filters(meta, app_args);
+    let force_resample = norm_changes_sample_rate(&filters, app_args);
+    filters.extend(resample_filters(meta, app_args, force_resample));
+    let channel_args = channel_args(meta, app_args);
+
+    let copy_mode = copy_mode(
+        item,
+        meta,
+        app_args,
+        !filters.is_empty() || !channel_args.is_empty(),
+    );
+
     if !app_args.dry_run {
         std::fs::create_dir_all(dir_path)?;
     }
 
+    if copy_mode == CopyMode::File {
+        item.print_info("COPY", &format!("{input_filename} -> {output_path_str}"));
+        if !app_args.dry_run {
+            std::fs::copy(input_filename, &output_path)?;
+        }
+        return Ok(ConvResult::Filename(output_path_str.into()));
+    }
+
     let mut args = str_vec!["-hide_banner", "-nostats", "-loglevel", "warning", "-y"];
 
-    let mut audio_args = app_args.output_ext_type.audio_args();
+    let trim = if copy_mode == CopyMode::None {
+        trim_filter(item, meta)
+    } else {
+        None
+    };
+
+    let mut audio_args = if copy_mode == CopyMode::Stream {
+        app_args.output_ext_type.copy_args()
+    } else {
+        let mut audio_args = app_args.output_ext_type.audio_args();
+        if let Some(trim) = &trim {
+            filters.insert(0, trim.filter.clone());
+        }
+        if !filters.is_empty() {
+            audio_args.extend(str_vec!["-af", &filters.join(",")]);
+        }
+        audio_args.extend(channel_args);
+        audio_args
+    };
 
     add_meta(&mut audio_args, &meta.tags.album, "album");
     add_meta(&mut audio_args, &meta.tags.composer, "composer");
@@ -155,36 +406,58 @@ pub fn conv_item(
     add_meta(&mut audio_args, &meta.tags.date, "date");
     add_meta(&mut audio_args, &meta.tags.year, "year");
 
+    // the tags of a plain file are copied by ffmpeg as is
+    if item.cue.is_some() {
+        add_meta(&mut audio_args, &meta.tags.comment, "comment");
+        add_meta(
+            &mut audio_args,
+            &meta.tags.isrc,
+            app_args.output_ext_type.isrc_tag(),
+        );
+        add_meta(&mut audio_args, &meta.tags.catalog_number, "CATALOGNUMBER");
+    }
+
+    if !meta.tags.is_compilation.is_empty() {
+        add_meta(
+            &mut audio_args,
+            &meta.tags.album_artist_or_va,
+            "album_artist",
+        );
+        add_meta(&mut audio_args, "1", "compilation");
+    }
+
+    for (name, val) in meta.replaygain.tags() {
+        if !val.is_empty() {
+            add_meta(&mut audio_args, val, name);
+        } else if item.cue.is_some() || app_args.replaygain != ReplayGainMode::Off {
+            // do not copy the values of the whole cue-sheet image,
+            // or the values that were not recalculated
+            audio_args.extend(str_vec!["-metadata", &format!("{name}=")]);
+        }
+    }
+
+    if item.cue.is_some() {
+        // the cue sheet embedded into the image does not belong to a single track
+        audio_args.extend(str_vec!["-metadata", "CUESHEET="]);
+    }
+
     if !meta.tags.track.is_empty() && !meta.tags.tracks.is_empty() {
         add_meta(
             &mut audio_args,
-            &(meta.tags.track + "/" + &meta.tags.tracks),
+            &(meta.tags.track.clone() + "/" + &meta.tags.tracks),
             "track",
         );
     } else {
         add_meta(&mut audio_args, &meta.tags.track, "track");
     }
 
-    let start_str;
-    let duration_str;
-    if let Some(cue) = &item.cue {
-        start_str = format!("{:.3}", cue.start);
-        args.extend(str_vec!["-ss:a", &start_str]);
-
-        duration_str = if let Some(duration) = cue.duration {
-            format!("{duration:.3}")
-        } else {
-            String::default()
-        };
-
-        if !duration_str.is_empty() {
-            args.extend(str_vec!["-t:a", &duration_str]);
-        }
-    }
     let output;
 
-    args.extend(str_vec!["-i", &input_filename]);
-    if meta.has_pic && app_args.use_embed_pic {
+    let (input_args, _concat_list) = input_args(item, app_args.dry_run, trim.as_ref())?;
+    args.extend(input_args);
+    let embed_pic = meta.has_pic && app_args.use_embed_pic;
+    let mut ogg_pic_data = None;
+    if embed_pic && matches!(app_args.output_ext_type, Format::MP3) {
         args.extend(audio_args);
 
         let pic_args = ffmpeg_conv_pic_args(app_args);
@@ -204,32 +477,12 @@ pub fn conv_item(
         }
     } else {
         let output_pic_data;
-        let mut cover_names = app_args.cover_names.clone();
-        if app_args.cover_name_from_dirname {
-            let dirname = canonical_dir
-                .file_name()
-                .ok_or("Can't get a dirname from the canonical path")?
-                .to_str()
-                .ok_or("Can't convert a dirname to string")?
-                .to_lowercase()
-                .trim()
-                .to_string();
-            cover_names.insert(0, dirname);
-        }
-        if app_args.cover_name_from_filename {
-            let basename = Path::new(input_filename)
-                .file_stem()
-                .ok_or("Can't get a file basename from the canonical path")?
-                .to_str()
-                .ok_or("Can't convert a file basename to string")?
-                .to_lowercase()
-                .trim()
-                .to_string();
-            cover_names.insert(0, basename);
-        }
-        if let Some(input_pic_filename) =
-            find_cover_in_dir(input_dir, &cover_names, &app_args.cover_exts)
-        {
+        if embed_pic {
+            output_pic_data = pics.extract_pic_if_needed(input_filename, app_args, progs);
+            if output_pic_data.is_none() {
+                return Err(format!("can't extract the cover art: {input_filename}").into());
+            }
+        } else if let Some(input_pic_filename) = find_cover(input_filename, app_args)? {
             output_pic_data = pics.conv_pic_if_needed(&input_pic_filename, app_args, progs);
             if output_pic_data.is_none() {
                 return Err(format!("can't convert: {}", &input_pic_filename).into());
@@ -238,7 +491,9 @@ pub fn conv_item(
             output_pic_data = None;
         }
 
-        if let Some(output_pic_data) = output_pic_data {
+        if let Some(output_pic_data) = &output_pic_data
+            && matches!(app_args.output_ext_type, Format::MP3)
+        {
             args.extend(str_vec!["-i", "-"]);
             args.extend(audio_args);
             args.extend(str_vec![
@@ -249,18 +504,10 @@ pub fn conv_item(
                 "-metadata:s:v",
                 "title=Album cover",
                 "-metadata:s:v",
-                "comment=Cover (front)"
+                "comment=Cover (front)",
+                "-c:v",
+                "copy"
             ]);
-            match app_args.output_ext_type {
-                Format::MP3 => {
-                    args.extend(str_vec!["-c:v", "copy"]);
-                }
-                Format::Ogg => {
-                    args.extend(str_vec!["-c:v", "libtheora"]);
-                    let pic_conv_args = ffmpeg_conv_pic_args(app_args);
-                    args.extend(pic_conv_args);
-                }
-            }
             let mut args = args
                 .iter()
                 .chain(&app_args.ffmpeg_opts)
@@ -278,13 +525,16 @@ pub fn conv_item(
                     .stderr(std::process::Stdio::piped())
                     .spawn()?;
                 if let Some(stdin) = proc.stdin.as_mut() {
-                    stdin.write_all(&output_pic_data)?;
+                    stdin.write_all(output_pic_data)?;
                     stdin.flush()?;
                 }
                 output = Some(proc.wait_with_output()?);
             }
         } else {
+            // Ogg gets the cover art as a Vorbis comment after the audio is encoded
+            ogg_pic_data = output_pic_data;
             args.extend(audio_args);
+            args.extend(str_vec!["-map", "0:a"]);
             let mut args = args
                 .iter()
                 .chain(&app_args.ffmpeg_opts)
@@ -307,5 +557,40 @@ pub fn conv_item(
         return Err(std::str::from_utf8(&output.stderr)?.into());
     }
 
-    return Ok(output_path_str.into());
+    if let Some(ogg_pic_data) = ogg_pic_data {
+        item.print_info("PIC", "METADATA_BLOCK_PICTURE");
+        if !app_args.dry_run {
+            write_ogg_cover(output_path_str, &ogg_pic_data)?;
+        }
+    }
+
+    let multi_values = [
+        ("artist", &meta.tags.artist),
+        ("author", &meta.tags.author),
+        ("composer", &meta.tags.composer),
+        ("genre", &meta.tags.genre),
+        ("label", &meta.tags.label),
+        ("lyricist", &meta.tags.lyricist),
+        ("performer", &meta.tags.performer),
+        ("publisher", &meta.tags.publisher),
+        ("songwriter", &meta.tags.songwriter),
+    ]
+    .into_iter()
+    .map(|(name, val)| TagValues {
+        name,
+        values: tag_values(val),
+    })
+    .filter(|tag| tag.values.len() > 1)
+    .collect::<Vec<TagValues>>();
+
+    if !multi_values.is_empty() {
+        for tag in &multi_values {
+            item.print_info("TAG", &format!("{}: {}", tag.name, tag.values.join(" | ")));
+        }
+        if !app_args.dry_run {
+            write_multi_values(output_path_str, &app_args.output_ext_type, &multi_values)?;
+        }
+    }
+
+    return Ok(ConvResult::Filename(output_path_str.into()));
 }
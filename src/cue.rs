@@ -1,21 +1,42 @@
 // SPDX-License-Identifier: GPL-3.0-only
 // 🄯 2021, Alexey Parfenov <zxed@alkatrazstudio.net>
 
-use cuna::Cuna;
-use cuna::track::Track;
+use chardetng::EncodingDetector;
+use cuna::parser::Command;
+use encoding_rs::{Encoding, UTF_8};
 use regex::Regex;
 use std::char::REPLACEMENT_CHARACTER;
 use std::error::Error;
 use std::fs::File;
 use std::io::Read;
-use std::path::Path;
-use std::str::FromStr;
+use std::path::{Path, PathBuf};
 
 const CUE_FRAMES_IN_SECOND: u8 = 75;
 
+// where the pregap (between INDEX 00 and INDEX 01) of a track goes
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum PregapPolicy {
+    Append,
+    Prepend,
+    Discard,
+}
+
+pub struct CueOptions {
+    pub encoding: Option<&'static Encoding>,
+    pub pregap: PregapPolicy,
+    pub htoa: bool,
+}
+
+// a continuous part of a single audio file, in cue frames
+pub struct CueSegment {
+    pub filename: String,
+    pub start_frame: u32,
+    pub end_frame: Option<u32>,
+}
+
 pub struct CueInfo {
-    pub start: f64,
-    pub duration: Option<f64>,
+    pub cue_filename: String,
+    pub segments: Vec<CueSegment>,
     pub album: String,
     pub title: String,
     pub performer: String,
@@ -27,103 +48,444 @@ pub struct CueInfo {
     pub total_discs: String,
     pub track: String,
     pub tracks: String,
+    pub isrc: String,
+    pub catalog: String,
+    pub composer: String,
+    pub comment: String,
+}
+
+pub struct CueIndex {
+    pub number: u8,
+    pub file: usize,
+    pub frames: u32,
+}
+
+#[derive(Default)]
+pub struct CueTrack {
+    pub number: u8,
+    pub title: String,
+    pub performer: String,
+    pub songwriter: String,
+    pub isrc: String,
+    pub comments: Vec<String>,
+    pub indexes: Vec<CueIndex>,
+}
+
+#[derive(Default)]
+pub struct CueSheet {
+    pub files: Vec<String>,
+    pub title: String,
+    pub performer: String,
+    pub songwriter: String,
+    pub catalog: String,
+    pub comments: Vec<String>,
+    pub tracks: Vec<CueTrack>,
+}
+
+// a position inside the audio files of a cue sheet
+#[derive(Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct CuePos {
+    file: usize,
+    frames: u32,
+}
+
+pub struct DecodedText {
+    pub text: String,
+    pub encoding: &'static Encoding,
+    pub has_errors: bool,
+}
+
+// A BOM always takes precedence over the specified encoding.
+// Without both, valid UTF-8 is assumed to be UTF-8,
+// and anything else is guessed from the byte statistics (e.g. CP1251 or Shift-JIS).
+fn decode_text(buf: &[u8], encoding: Option<&'static Encoding>) -> DecodedText {
+    let (encoding, bom_len) = if let Some((encoding, bom_len)) = Encoding::for_bom(buf) {
+        (encoding, bom_len)
+    } else if let Some(encoding) = encoding {
+        (encoding, 0)
+    } else if std::str::from_utf8(buf).is_ok() {
+        (UTF_8, 0)
+    } else {
+        let mut detector = EncodingDetector::new();
+        detector.feed(buf, true);
+        (detector.guess(None, true), 0)
+    };
+
+    let (text, has_errors) = encoding.decode_without_bom_handling(&buf[bom_len..]);
+    return DecodedText {
+        text: text.replace(REPLACEMENT_CHARACTER, ""),
+        encoding,
+        has_errors,
+    };
 }
 
-fn read_string_from_file(path: &Path) -> Result<String, Box<dyn Error>> {
+pub fn read_string_from_file(
+    path: &Path,
+    encoding: Option<&'static Encoding>,
+) -> Result<DecodedText, Box<dyn Error>> {
     let mut file = File::open(path)?;
     let mut buf = Vec::new();
     file.read_to_end(&mut buf)?;
-    let s = String::from_utf8_lossy(&buf);
-    let s = s.replace(REPLACEMENT_CHARACTER, "");
-    return Ok(s);
-}
-
-fn open_cue(path: &Path) -> Result<Cuna, Box<dyn Error>> {
-    let s = read_string_from_file(path)?;
-    let rx = Regex::new(r"(?:^|\n)\s*FLAGS.*(?:\n|$)")?;
-    let s = rx.replace_all(&s, "\n").to_string();
-    let cue = Cuna::from_str(&s)?;
-    return Ok(cue);
-}
-
-fn find_cue_info_in_file(filename: &Path) -> Option<Vec<CueInfo>> {
-    if !filename.exists() {
-        return None;
-    }
-
-    match open_cue(filename) {
-        Ok(cue) => {
-            if let Some(file) = cue.first_file() {
-                let mut infos = Vec::new();
-                let max_track_index = max_track_index(&file.tracks);
-                for track in &file.tracks {
-                    let next_track = track_by_index(&file.tracks, track.id() + 1);
-                    if let Some(info) = cue_track_info(track, next_track, max_track_index, &cue) {
-                        infos.push(info);
-                    }
+    return Ok(decode_text(&buf, encoding));
+}
+
+fn parse_cue(s: &str) -> Result<CueSheet, Box<dyn Error>> {
+    let mut sheet = CueSheet::default();
+
+    for (line_index, line) in s.lines().enumerate() {
+        let line_err = |e: &dyn std::fmt::Display| format!("{} at line {}", e, line_index + 1);
+        let command = Command::new(line).map_err(|e| line_err(&e))?;
+        match command {
+            Command::Rem(s) => match sheet.tracks.last_mut() {
+                Some(track) => track.comments.push(s.to_owned()),
+                None => sheet.comments.push(s.to_owned()),
+            },
+            Command::Title(s) => match sheet.tracks.last_mut() {
+                Some(track) => s.clone_into(&mut track.title),
+                None => s.clone_into(&mut sheet.title),
+            },
+            Command::Performer(s) => match sheet.tracks.last_mut() {
+                Some(track) => s.clone_into(&mut track.performer),
+                None => s.clone_into(&mut sheet.performer),
+            },
+            Command::Songwriter(s) => match sheet.tracks.last_mut() {
+                Some(track) => s.clone_into(&mut track.songwriter),
+                None => s.clone_into(&mut sheet.songwriter),
+            },
+            Command::Catalog(catalog) => sheet.catalog = format!("{catalog:013}"),
+            Command::Isrc(s) => {
+                if let Some(track) = sheet.tracks.last_mut() {
+                    s.trim().clone_into(&mut track.isrc);
+                }
+            }
+            Command::File(name, _) => sheet.files.push(name.to_owned()),
+            Command::Track(number, _) => {
+                if sheet.files.is_empty() {
+                    return Err(line_err(&"TRACK before FILE").into());
+                }
+                sheet.tracks.push(CueTrack {
+                    number,
+                    ..Default::default()
+                });
+            }
+            Command::Index(number, timestamp) => {
+                // a new FILE may start in the middle of a track,
+                // e.g. INDEX 00 is in the previous file and INDEX 01 is in the new one
+                let file = sheet.files.len() - 1;
+                match sheet.tracks.last_mut() {
+                    Some(track) => track.indexes.push(CueIndex {
+                        number,
+                        file,
+                        frames: timestamp.total_frames(),
+                    }),
+                    None => return Err(line_err(&"INDEX before TRACK").into()),
                 }
-                return Some(infos);
             }
+            _ => {}
         }
-
-        Err(e) => println!("{e}"),
     }
 
-    return None;
+    return Ok(sheet);
+}
+
+pub struct CueFile {
+    pub filename: String,
+    pub encoding: &'static Encoding,
+    pub has_decode_errors: bool,
+    sheet: CueSheet,
+}
+
+pub fn open_cue(
+    path: &Path,
+    encoding: Option<&'static Encoding>,
+) -> Result<CueFile, Box<dyn Error>> {
+    let decoded = read_string_from_file(path, encoding)?;
+    let sheet = parse_cue(&decoded.text)?;
+    return Ok(CueFile {
+        filename: path
+            .to_str()
+            .ok_or("Can't convert path to string")?
+            .to_string(),
+        encoding: decoded.encoding,
+        has_decode_errors: decoded.has_errors,
+        sheet,
+    });
 }
 
-pub fn find_cue_info(path: &Path) -> Option<Vec<CueInfo>> {
-    let cue_filename = path.with_extension("cue");
-    if let Some(info) = find_cue_info_in_file(&cue_filename) {
-        return Some(info);
+// a cue sheet embedded into the tags of the audio file
+pub fn parse_embedded_cue(text: &str, audio_filename: &str) -> Result<CueFile, Box<dyn Error>> {
+    let sheet = parse_cue(text)?;
+    return Ok(CueFile {
+        filename: audio_filename.to_string(),
+        encoding: UTF_8,
+        has_decode_errors: false,
+        sheet,
+    });
+}
+
+fn lowercase_name(path: &Path) -> String {
+    return path.to_str().unwrap_or_default().to_lowercase();
+}
+
+fn has_ext(path: &Path, exts: &[String]) -> bool {
+    return path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| exts.contains(&ext.to_lowercase()));
+}
+
+// The name is matched case-insensitively.
+// If not found, a file with the same name but any of the input extensions is searched,
+// e.g. when the referenced WAV image was recompressed to FLAC.
+fn resolve_file(cue_dir: &Path, name: &str, exts: &[String]) -> Option<PathBuf> {
+    let path = cue_dir.join(name.replace('\\', "/"));
+    let dir = path.parent()?;
+    let file_name = lowercase_name(Path::new(path.file_name()?));
+    let file_stem = lowercase_name(Path::new(path.file_stem()?));
+
+    let mut entries = dir
+        .read_dir()
+        .ok()?
+        .flatten()
+        .filter(|entry| entry.file_type().is_ok_and(|t| t.is_file()))
+        .map(|entry| entry.path())
+        .filter(|entry| has_ext(entry, exts))
+        .collect::<Vec<PathBuf>>();
+    entries.sort();
+
+    let by_name = |entry: &&PathBuf| {
+        return entry
+            .file_name()
+            .is_some_and(|name| lowercase_name(Path::new(name)) == file_name);
+    };
+    let by_stem = |entry: &&PathBuf| {
+        return entry
+            .file_stem()
+            .is_some_and(|stem| lowercase_name(Path::new(stem)) == file_stem);
+    };
+
+    let found = entries
+        .iter()
+        .find(by_name)
+        .or_else(|| entries.iter().find(by_stem))?;
+    return Some(found.clone());
+}
+
+impl CueFile {
+    pub const fn files_count(&self) -> usize {
+        return self.sheet.files.len();
     }
 
-    if let Some(cue_filename) = path.to_str() {
-        let cue_filename = cue_filename.to_string() + ".cue";
-        let cue_filename = Path::new(&cue_filename);
-        if let Some(info) = find_cue_info_in_file(cue_filename) {
-            return Some(info);
+    // every referenced file, or None if it's not found
+    pub fn find_files(&self, exts: &[String]) -> Vec<Option<String>> {
+        let cue_dir = Path::new(&self.filename)
+            .parent()
+            .unwrap_or_else(|| Path::new(""));
+        return self
+            .sheet
+            .files
+            .iter()
+            .map(|name| {
+                let path = resolve_file(cue_dir, name, exts)?;
+                return path.to_str().map(ToString::to_string);
+            })
+            .collect();
+    }
+
+    pub fn resolve_files(&self, exts: &[String]) -> Result<Vec<String>, Box<dyn Error>> {
+        let mut filenames = Vec::new();
+        for (name, filename) in self.sheet.files.iter().zip(self.find_files(exts)) {
+            let filename = filename.ok_or_else(|| format!("referenced file not found: {name}"))?;
+            filenames.push(filename);
         }
+        return Ok(filenames);
     }
 
-    return None;
-}
+    // Problems of the cue sheet itself and of the files it references.
+    // The durations are in seconds, one for every resolved file.
+    pub fn problems(&self, filenames: &[Option<String>], durations: &[Option<f64>]) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        if self.has_decode_errors {
+            problems.push(format!(
+                "some characters can't be decoded as {}",
+                self.encoding.name()
+            ));
+        }
 
-fn max_track_index(tracks: &[Track]) -> u8 {
-    let mut max_id = 0;
-    for track in tracks {
-        if track.id() > max_id {
-            max_id = track.id();
+        if self.sheet.files.is_empty() {
+            problems.push("no FILE entries".to_string());
         }
+        for (name, filename) in self.sheet.files.iter().zip(filenames) {
+            if filename.is_none() {
+                problems.push(format!("referenced file not found: {name}"));
+            }
+        }
+
+        if self.sheet.tracks.is_empty() {
+            problems.push("no tracks".to_string());
+        }
+
+        let mut prev_track: Option<(u8, CuePos)> = None;
+        for track in &self.sheet.tracks {
+            let n = track.number;
+
+            if track.title.trim().is_empty() {
+                problems.push(format!("track {n}: no TITLE"));
+            }
+
+            for pair in track.indexes.windows(2) {
+                let pos = |index: &CueIndex| CuePos {
+                    file: index.file,
+                    frames: index.frames,
+                };
+                if pair[1].number <= pair[0].number || pos(&pair[1]) <= pos(&pair[0]) {
+                    problems.push(format!(
+                        "track {n}: INDEX {:02} is not after INDEX {:02}",
+                        pair[1].number, pair[0].number
+                    ));
+                }
+            }
+
+            let Some(start) = index_pos(track, 1) else {
+                problems.push(format!("track {n}: no INDEX 01"));
+                continue;
+            };
+
+            if let Some((prev_n, prev_start)) = prev_track {
+                if n <= prev_n {
+                    problems.push(format!(
+                        "track {n}: the track number is not greater than {prev_n}"
+                    ));
+                }
+                if start <= prev_start {
+                    problems.push(format!("track {n}: does not start after track {prev_n}"));
+                }
+            }
+            prev_track = Some((n, start));
+
+            if let Some(Some(duration)) = durations.get(start.file)
+                && frames_to_secs(start.frames) >= *duration
+            {
+                problems.push(format!(
+                    "track {n}: starts past the end of {} ({duration:.3} s)",
+                    self.sheet.files[start.file]
+                ));
+            }
+        }
+
+        return problems;
+    }
+
+    // Returns the info for every track along with the file of its INDEX 01.
+    pub fn tracks(&self, filenames: &[String], options: &CueOptions) -> Vec<(String, CueInfo)> {
+        return cue_track_infos(&self.sheet, &self.filename, filenames, options)
+            .into_iter()
+            .map(|(file, info)| (filenames[file].clone(), info))
+            .collect();
     }
-    return max_id;
 }
 
-fn track_by_index(tracks: &[Track], id: u8) -> Option<&Track> {
-    let track = tracks.iter().find(|&track| track.id() == id);
-    return track;
+fn index_pos(track: &CueTrack, number: u8) -> Option<CuePos> {
+    return track
+        .indexes
+        .iter()
+        .find(|index| index.number == number)
+        .map(|index| CuePos {
+            file: index.file,
+            frames: index.frames,
+        });
 }
 
-fn track_start(track: &Track) -> Option<u32> {
-    for i in &track.index {
-        if i.id() == 1 {
-            return Some(i.begin_time.total_frames());
-        }
+// the start of the pregap (INDEX 00) or the track itself (INDEX 01) if there's no pregap
+fn pregap_start(track: &CueTrack) -> Option<CuePos> {
+    let start = index_pos(track, 1)?;
+    let pregap = index_pos(track, 0).filter(|&pregap| pregap < start);
+    return Some(pregap.unwrap_or(start));
+}
+
+fn track_start(track: &CueTrack, pregap: PregapPolicy) -> Option<CuePos> {
+    return match pregap {
+        PregapPolicy::Prepend => pregap_start(track),
+        PregapPolicy::Append | PregapPolicy::Discard => index_pos(track, 1),
+    };
+}
+
+fn track_end(next_track: &CueTrack, pregap: PregapPolicy) -> Option<CuePos> {
+    return match pregap {
+        PregapPolicy::Append => index_pos(next_track, 1),
+        PregapPolicy::Prepend | PregapPolicy::Discard => pregap_start(next_track),
+    };
+}
+
+fn frames_to_secs(frames: u32) -> f64 {
+    return f64::from(frames) / f64::from(CUE_FRAMES_IN_SECOND);
+}
+
+// Rounded to the nearest cue frame.
+pub fn secs_to_frames(secs: f64) -> u32 {
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    let frames = (secs * f64::from(CUE_FRAMES_IN_SECOND)).round() as u32;
+    return frames;
+}
+
+fn frames_to_samples(frames: u32, sample_rate: u32) -> u64 {
+    return u64::from(frames) * u64::from(sample_rate) / u64::from(CUE_FRAMES_IN_SECOND);
+}
+
+impl CueSegment {
+    pub fn start(&self) -> f64 {
+        return frames_to_secs(self.start_frame);
+    }
+
+    pub fn duration(&self) -> Option<f64> {
+        return self
+            .end_frame
+            .map(|end_frame| frames_to_secs(end_frame - self.start_frame));
+    }
+
+    pub fn start_sample(&self, sample_rate: u32) -> u64 {
+        return frames_to_samples(self.start_frame, sample_rate);
+    }
+
+    pub fn end_sample(&self, sample_rate: u32) -> Option<u64> {
+        return self
+            .end_frame
+            .map(|end_frame| frames_to_samples(end_frame, sample_rate));
     }
-    return None;
 }
 
-fn opt_str(s: &[String], def: &str) -> String {
-    if let Some(s) = s.first() {
-        return s.trim().to_owned();
+// Splits the range between two positions into the parts of the separate files.
+// The end of the last track is the end of its file.
+fn cue_segments(start: CuePos, end: Option<CuePos>, filenames: &[String]) -> Vec<CueSegment> {
+    let mut segments = Vec::new();
+    let last_file = end.map_or(start.file, |end| end.file);
+
+    for (file, filename) in filenames
+        .iter()
+        .enumerate()
+        .take(last_file + 1)
+        .skip(start.file)
+    {
+        let seg_start = if file == start.file { start.frames } else { 0 };
+        let seg_end = end.filter(|end| end.file == file).map(|end| end.frames);
+        if seg_end.is_some_and(|seg_end| seg_end <= seg_start) {
+            continue;
+        }
+        segments.push(CueSegment {
+            filename: filename.clone(),
+            start_frame: seg_start,
+            end_frame: seg_end,
+        });
     }
-    return def.to_owned();
+
+    return segments;
 }
 
-fn extract_comment(cd: &Cuna, tag: &str) -> String {
+fn extract_comment(comments: &[String], tag: &str) -> String {
     let rx_str = String::from(r"(?i)^") + &regex::escape(tag) + r#"\s+(.+)"?$"#;
     let rx = Regex::new(&rx_str).unwrap();
-    for comment in &cd.comments.0 {
+    for comment in comments {
         if let Some(m) = rx.captures(comment)
             && let Some(m) = m.get(1)
         {
@@ -138,40 +500,320 @@ fn extract_comment(cd: &Cuna, tag: &str) -> String {
     return String::default();
 }
 
-fn cue_track_info(
-    track: &Track,
-    next_track: Option<&Track>,
-    max_track_index: u8,
-    cd: &Cuna,
-) -> Option<CueInfo> {
-    if let Some(start) = track_start(track) {
-        let duration = if let Some(next_track) = next_track
-            && let Some(next_start) = track_start(next_track)
-            && next_start > start
-        {
-            Some(next_start - start)
+fn or_default(s: &str, def: &str) -> String {
+    let s = s.trim();
+    if s.is_empty() {
+        return def.trim().to_owned();
+    }
+    return s.to_owned();
+}
+
+// Returns the info for every track along with the index of the file with its INDEX 01.
+fn cue_track_infos(
+    sheet: &CueSheet,
+    cue_filename: &str,
+    filenames: &[String],
+    options: &CueOptions,
+) -> Vec<(usize, CueInfo)> {
+    let max_track_number = sheet.tracks.iter().map(|track| track.number).max();
+    let max_track_number = max_track_number.unwrap_or_default();
+
+    let track_info = |track: &CueTrack, number: u8, segments: Vec<CueSegment>| {
+        // the comments of a track take precedence over the ones of the whole disc
+        let comment = |tag: &str| {
+            return or_default(
+                &extract_comment(&track.comments, tag),
+                &extract_comment(&sheet.comments, tag),
+            );
+        };
+
+        return CueInfo {
+            cue_filename: cue_filename.to_string(),
+            segments,
+            album: sheet.title.trim().to_owned(),
+            title: track.title.trim().to_owned(),
+            performer: or_default(&track.performer, &sheet.performer),
+            songwriter: or_default(&track.songwriter, &sheet.songwriter),
+            genre: comment("GENRE"),
+            date: comment("DATE"),
+            disc_id: extract_comment(&sheet.comments, "DISCID"),
+            disc_number: extract_comment(&sheet.comments, "DISCNUMBER"),
+            total_discs: extract_comment(&sheet.comments, "TOTALDISCS"),
+            track: number.to_string(),
+            tracks: max_track_number.to_string(),
+            isrc: track.isrc.clone(),
+            catalog: sheet.catalog.clone(),
+            composer: comment("COMPOSER"),
+            comment: comment("COMMENT"),
+        };
+    };
+
+    let mut infos = Vec::new();
+
+    // the hidden track before the first one (HTOA) is the pregap of the first track
+    let htoa_end = sheet
+        .tracks
+        .first()
+        .and_then(|track| index_pos(track, 1))
+        .filter(|&end| options.htoa && end > CuePos::default());
+    if let Some(end) = htoa_end {
+        let segments = cue_segments(CuePos::default(), Some(end), filenames);
+        infos.push((0, track_info(&CueTrack::default(), 0, segments)));
+    }
+
+    for (i, track) in sheet.tracks.iter().enumerate() {
+        let start = if i == 0 && htoa_end.is_some() {
+            index_pos(track, 1)
         } else {
-            None
+            track_start(track, options.pregap)
         };
 
-        let duration =
-            duration.map(|duration| f64::from(duration) / f64::from(CUE_FRAMES_IN_SECOND));
-
-        return Some(CueInfo {
-            start: f64::from(start) / f64::from(CUE_FRAMES_IN_SECOND),
-            duration,
-            album: opt_str(cd.title(), ""),
-            title: opt_str(track.title(), ""),
-            performer: opt_str(track.performer(), &opt_str(cd.performer(), "")),
-            songwriter: opt_str(track.songwriter(), &opt_str(cd.songwriter(), "")),
-            genre: extract_comment(cd, "GENRE"),
-            date: extract_comment(cd, "DATE"),
-            disc_id: extract_comment(cd, "DISCID"),
-            disc_number: extract_comment(cd, "DISCNUMBER"),
-            total_discs: extract_comment(cd, "TOTALDISCS"),
-            track: track.id().to_string().trim().to_string(),
-            tracks: max_track_index.to_string().trim().to_string(),
-        });
+        if let Some(start) = start
+            && let Some(main) = index_pos(track, 1)
+        {
+            let end = sheet.tracks[i + 1..]
+                .iter()
+                .find_map(|next_track| track_end(next_track, options.pregap))
+                .filter(|&end| end > start);
+
+            let segments = cue_segments(start, end, filenames);
+            infos.push((main.file, track_info(track, track.number, segments)));
+        }
+    }
+
+    return infos;
+}
+
+// cue strings can't contain double quotes
+pub fn cue_quoted(s: &str) -> String {
+    return format!("\"{}\"", s.replace('"', "'"));
+}
+
+fn frames_to_timestamp(frames: u32) -> String {
+    let frames_in_second = u32::from(CUE_FRAMES_IN_SECOND);
+    let secs = frames / frames_in_second;
+    return format!(
+        "{:02}:{:02}:{:02}",
+        secs / 60,
+        secs % 60,
+        frames % frames_in_second
+    );
+}
+
+impl CueSheet {
+    pub fn render(&self) -> String {
+        let mut lines = Vec::new();
+        lines.extend(self.comments.iter().map(|comment| format!("REM {comment}")));
+        if !self.catalog.is_empty() {
+            lines.push(format!("CATALOG {}", self.catalog));
+        }
+        if !self.performer.is_empty() {
+            lines.push(format!("PERFORMER {}", cue_quoted(&self.performer)));
+        }
+        if !self.title.is_empty() {
+            lines.push(format!("TITLE {}", cue_quoted(&self.title)));
+        }
+        if !self.songwriter.is_empty() {
+            lines.push(format!("SONGWRITER {}", cue_quoted(&self.songwriter)));
+        }
+
+        let mut file = None;
+        for track in &self.tracks {
+            let track_file = track.indexes.first().map(|index| index.file);
+            if track_file.is_some() && track_file != file {
+                file = track_file;
+                if let Some(name) = file.and_then(|file| self.files.get(file)) {
+                    lines.push(format!("FILE {} WAVE", cue_quoted(name)));
+                }
+            }
+
+            lines.push(format!("  TRACK {:02} AUDIO", track.number));
+            if !track.title.is_empty() {
+                lines.push(format!("    TITLE {}", cue_quoted(&track.title)));
+            }
+            if !track.performer.is_empty() {
+                lines.push(format!("    PERFORMER {}", cue_quoted(&track.performer)));
+            }
+            if !track.songwriter.is_empty() {
+                lines.push(format!("    SONGWRITER {}", cue_quoted(&track.songwriter)));
+            }
+            if !track.isrc.is_empty() {
+                lines.push(format!("    ISRC {}", track.isrc));
+            }
+            lines.extend(
+                track
+                    .comments
+                    .iter()
+                    .map(|comment| format!("    REM {comment}")),
+            );
+            for index in &track.indexes {
+                lines.push(format!(
+                    "    INDEX {:02} {}",
+                    index.number,
+                    frames_to_timestamp(index.frames)
+                ));
+            }
+        }
+
+        return lines.join("\n") + "\n";
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use encoding_rs::{SHIFT_JIS, UTF_16LE, WINDOWS_1251};
+
+    const RUSSIAN: &str = "Песня о встречном ветре, который дул всю ночь над рекой";
+
+    #[test]
+    fn decode_bom_takes_precedence() {
+        let mut buf = b"\xEF\xBB\xBF".to_vec();
+        buf.extend(RUSSIAN.as_bytes());
+        let decoded = decode_text(&buf, Some(WINDOWS_1251));
+        assert_eq!(decoded.encoding, UTF_8);
+        assert_eq!(decoded.text, RUSSIAN);
+        assert!(!decoded.has_errors);
+
+        let mut buf = b"\xFF\xFE".to_vec();
+        buf.extend(RUSSIAN.encode_utf16().flat_map(u16::to_le_bytes));
+        let decoded = decode_text(&buf, None);
+        assert_eq!(decoded.encoding, UTF_16LE);
+        assert_eq!(decoded.text, RUSSIAN);
+    }
+
+    #[test]
+    fn decode_override() {
+        let (buf, _, _) = WINDOWS_1251.encode(RUSSIAN);
+        let decoded = decode_text(&buf, Some(WINDOWS_1251));
+        assert_eq!(decoded.encoding, WINDOWS_1251);
+        assert_eq!(decoded.text, RUSSIAN);
+
+        // the undecodable characters are dropped
+        let decoded = decode_text(&buf, Some(UTF_8));
+        assert!(decoded.has_errors);
+        assert!(!decoded.text.contains(REPLACEMENT_CHARACTER));
+    }
+
+    #[test]
+    fn decode_detected() {
+        let decoded = decode_text(RUSSIAN.as_bytes(), None);
+        assert_eq!(decoded.encoding, UTF_8);
+
+        let (buf, _, _) = WINDOWS_1251.encode(RUSSIAN);
+        let decoded = decode_text(&buf, None);
+        assert_eq!(decoded.encoding, WINDOWS_1251);
+        assert_eq!(decoded.text, RUSSIAN);
+
+        let japanese = "夜空に輝く星を見上げながら、私たちは静かに歌を歌った。";
+        let (buf, _, _) = SHIFT_JIS.encode(japanese);
+        let decoded = decode_text(&buf, None);
+        assert_eq!(decoded.encoding, SHIFT_JIS);
+        assert_eq!(decoded.text, japanese);
+    }
+
+    const SHEET: &str = r#"FILE "a.wav" WAVE
+  TRACK 01 AUDIO
+    TITLE "One"
+    INDEX 01 00:10:00
+  TRACK 02 AUDIO
+    TITLE "Two"
+    INDEX 00 03:00:00
+    INDEX 01 03:02:00
+  TRACK 03 AUDIO
+    TITLE "Three"
+    INDEX 00 05:00:00
+FILE "b.wav" WAVE
+    INDEX 01 00:00:00
+  TRACK 04 AUDIO
+    TITLE "Four"
+    INDEX 01 04:00:00
+"#;
+
+    // (file of INDEX 01, [(filename, start frame, end frame)])
+    type Split = Vec<(usize, Vec<(String, u32, Option<u32>)>)>;
+
+    fn split(pregap: PregapPolicy, htoa: bool) -> Split {
+        let sheet = parse_cue(SHEET).unwrap();
+        let filenames = str_vec!["a.wav", "b.wav"];
+        let options = CueOptions {
+            encoding: None,
+            pregap,
+            htoa,
+        };
+        return cue_track_infos(&sheet, "x.cue", &filenames, &options)
+            .into_iter()
+            .map(|(file, info)| {
+                let segments = info
+                    .segments
+                    .iter()
+                    .map(|seg| (seg.filename.clone(), seg.start_frame, seg.end_frame))
+                    .collect();
+                return (file, segments);
+            })
+            .collect();
+    }
+
+    fn seg(filename: &str, start: u32, end: Option<u32>) -> (String, u32, Option<u32>) {
+        return (filename.to_string(), start, end);
+    }
+
+    #[test]
+    fn htoa() {
+        let tracks = split(PregapPolicy::Prepend, true);
+        assert_eq!(tracks.len(), 5);
+        assert_eq!(tracks[0], (0, vec![seg("a.wav", 0, Some(750))]));
+        assert_eq!(tracks[1], (0, vec![seg("a.wav", 750, Some(13500))]));
+
+        // the hidden track is discarded even if the pregaps are prepended
+        let tracks = split(PregapPolicy::Prepend, false);
+        assert_eq!(tracks.len(), 4);
+        assert_eq!(tracks[0], (0, vec![seg("a.wav", 750, Some(13500))]));
+    }
+
+    #[test]
+    fn pregap_append() {
+        let tracks = split(PregapPolicy::Append, false);
+        assert_eq!(
+            tracks,
+            vec![
+                (0, vec![seg("a.wav", 750, Some(13650))]),
+                (0, vec![seg("a.wav", 13650, None)]),
+                (1, vec![seg("b.wav", 0, Some(18000))]),
+                (1, vec![seg("b.wav", 18000, None)]),
+            ]
+        );
+    }
+
+    #[test]
+    fn pregap_prepend() {
+        // the pregap of the third track is at the end of the first file
+        let tracks = split(PregapPolicy::Prepend, false);
+        assert_eq!(
+            tracks,
+            vec![
+                (0, vec![seg("a.wav", 750, Some(13500))]),
+                (0, vec![seg("a.wav", 13500, Some(22500))]),
+                (
+                    1,
+                    vec![seg("a.wav", 22500, None), seg("b.wav", 0, Some(18000))]
+                ),
+                (1, vec![seg("b.wav", 18000, None)]),
+            ]
+        );
+    }
+
+    #[test]
+    fn pregap_discard() {
+        let tracks = split(PregapPolicy::Discard, false);
+        assert_eq!(
+            tracks,
+            vec![
+                (0, vec![seg("a.wav", 750, Some(13500))]),
+                (0, vec![seg("a.wav", 13650, Some(22500))]),
+                (1, vec![seg("b.wav", 0, Some(18000))]),
+                (1, vec![seg("b.wav", 18000, None)]),
+            ]
+        );
     }
-    return None;
 }
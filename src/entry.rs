@@ -1,39 +1,135 @@
 // SPDX-License-Identifier: GPL-3.0-only
 // 🄯 2021, Alexey Parfenov <zxed@alkatrazstudio.net>
 
+use crate::album::Albums;
 use crate::args::{AppArgs, parse_cli_args};
-use crate::convert::{Item, Progs, conv_item, validate_template};
-use crate::files::{find_files, print_tree};
-use crate::pics::PicsMap;
+use crate::companions::copy_companions;
+use crate::convert::{
+    ConvResult, Item, OutputTrack, Progs, check_lossy_source, conv_item, validate_template,
+};
+use crate::files::{
+    InputFiles, expand_embedded_cues, find_files, input_files, print_tree, verify_cue_splits,
+};
+use crate::filters::filter_by_tags;
+use crate::lint::lint;
+use crate::loudness::measure_items;
+use crate::merge::merge_albums;
+use crate::meta::{FileMeta, extract_meta, probe_file};
+use crate::pic_cache::evict;
+use crate::pics::{PicsMap, export_covers};
+use crate::playlist::{rewrite_playlists, write_playlists};
+use crate::replaygain::apply_replaygain;
 use rayon::prelude::*;
 use std::error::Error;
 use std::path::Path;
 
 enum ItemResult {
     Filename(String),
+    Skipped(String),
     Error(String),
+    // merged into the image of the first track of the album
+    Merged,
 }
 
-fn run(items: &[Item], args: &AppArgs, progs: &Progs) -> Result<Vec<ItemResult>, Box<dyn Error>> {
+type RunResult = (Vec<Item>, Vec<Result<FileMeta, String>>, Vec<ItemResult>);
+
+fn run(
+    items: Vec<Item>,
+    pics: &PicsMap,
+    args: &AppArgs,
+    progs: &Progs,
+) -> Result<RunResult, Box<dyn Error>> {
     rayon::ThreadPoolBuilder::new()
         .num_threads(args.threads_count)
         .build_global()?;
 
-    let pics = PicsMap::new();
-    let filenames = items
+    let probes = items
         .par_iter()
         .map(|item| {
-            return match conv_item(item, &pics, args, progs) {
+            return probe_file(&item.filename, &progs.ffprobe_bin).map_err(|e| e.to_string());
+        })
+        .collect::<Vec<_>>();
+    let (items, probes) = expand_embedded_cues(items, probes, &args.cue);
+
+    let mut metas = items
+        .par_iter()
+        .zip(&probes)
+        .map(|(item, probe)| {
+            return probe
+                .as_ref()
+                .map(|probe| extract_meta(probe, &item.filename, item.cue.as_ref()))
+                .map_err(Clone::clone);
+        })
+        .collect::<Vec<_>>();
+    let albums = Albums::new(&items, &metas, &args.various_artists_name);
+    albums.apply_tags(&mut metas, &args.various_artists_name);
+    let (items, mut metas, albums) = filter_by_tags(items, metas, albums, &args.tag_filters);
+
+    verify_cue_splits(&items, &metas);
+
+    // the merged image is always lossless, so it's never lossy-to-lossy
+    if args.merge_template.is_none() {
+        for (item, meta) in items.iter().zip(metas.iter_mut()) {
+            if let Ok(file_meta) = meta {
+                match check_lossy_source(item, file_meta, args) {
+                    Ok(reason) => file_meta.skip_reason = reason,
+                    Err(e) => *meta = Err(e.to_string()),
+                }
+            }
+        }
+    }
+    measure_items(&items, &mut metas, &albums, args, progs);
+    apply_replaygain(&mut metas, args);
+
+    if let Some(merge_template) = &args.merge_template {
+        let mut results = metas
+            .iter()
+            .map(|meta| match meta {
+                Ok(_) => ItemResult::Merged,
+                Err(e) => ItemResult::Error(e.clone()),
+            })
+            .collect::<Vec<_>>();
+        let album_results = merge_albums(&items, &metas, &albums, merge_template, args, progs);
+        for (album, result) in albums.list.iter().zip(album_results) {
+            match result {
+                Ok(filename) => {
+                    if let Some(&i) = album.items.first() {
+                        results[i] = ItemResult::Filename(filename);
+                    }
+                }
+                // the tracks that have failed on their own keep their errors
+                Err(e) => {
+                    for &i in &album.items {
+                        if matches!(results[i], ItemResult::Merged) {
+                            results[i] = ItemResult::Error(format!("not merged: {e}"));
+                        }
+                    }
+                }
+            }
+        }
+        return Ok((items, metas, results));
+    }
+
+    let filenames = items
+        .par_iter()
+        .zip(&metas)
+        .map(|(item, meta)| {
+            let result = match meta {
+                Ok(meta) => conv_item(item, meta, pics, args, progs),
+                Err(e) => Err(e.as_str().into()),
+            };
+            return match result {
                 Err(e) => {
                     item.print_info("ERR", &e.to_string());
                     return ItemResult::Error(e.to_string());
                 }
-                Ok(filename) => ItemResult::Filename(filename),
+                Ok(ConvResult::Filename(filename)) => ItemResult::Filename(filename),
+                Ok(ConvResult::Skipped(reason)) => ItemResult::Skipped(reason),
             };
         })
         .collect();
 
-    return Ok(filenames);
+    return Ok((items, metas, filenames));
 }
 
 fn find_prog(name: &str, arg: Option<&String>) -> Result<String, Box<dyn Error>> {
@@ -70,13 +166,35 @@ fn find_progs(args: &AppArgs) -> Result<Progs, Box<dyn Error>> {
 pub fn main() -> Result<(), Box<dyn Error>> {
     let args = parse_cli_args()?;
     if let Some(args) = args {
-        validate_template(&args.filename_template)?;
+        if args.lint {
+            let progs = find_progs(&args)?;
+            return lint(&args, &progs);
+        }
+
+        for playlist_template in &args.playlist_templates {
+            validate_template(playlist_template)?;
+        }
+        if let Some(merge_template) = &args.merge_template {
+            validate_template(merge_template)?;
+        } else if args.filename_template.is_empty() {
+            return Err("--filename-template is required".into());
+        } else {
+            validate_template(&args.filename_template)?;
+        }
         let progs = find_progs(&args)?;
 
-        let items = find_files(&args.input_dirs, &args.input_exts)?;
-        let filenames = run(&items, &args, &progs)?;
+        let InputFiles {
+            audio_files,
+            cue_files,
+            playlists: source_playlists,
+        } = input_files(&args)?;
+        let items = find_files(audio_files, cue_files, &args);
+        let pics = PicsMap::new();
+        let (items, metas, filenames) = run(items, &pics, &args, &progs)?;
         let mut valid_filenames = Vec::new();
+        let mut outputs = Vec::new();
         let mut errs = Vec::new();
+        let mut skipped = Vec::new();
 
         let n = filenames.len();
 
@@ -100,9 +218,60 @@ pub fn main() -> Result<(), Box<dyn Error>> {
 
                     if !exists {
                         valid_filenames.push(filename);
+                        if let Ok(meta) = &metas[a] {
+                            outputs.push(OutputTrack {
+                                index: a,
+                                filename,
+                                meta,
+                            });
+                        }
                     }
                 }
+                ItemResult::Skipped(reason) => {
+                    skipped.push(format!("{}: {}", &items[a].filename, reason));
+                }
                 ItemResult::Error(e) => errs.push(format!("{}: {}", &items[a].filename, e)),
+                ItemResult::Merged => {}
+            }
+        }
+
+        let (mut playlists, playlist_errs) = write_playlists(&outputs, &args);
+        errs.extend(playlist_errs);
+        let mut unmapped = Vec::new();
+        if args.rewrite_playlists {
+            let rewritten =
+                rewrite_playlists(&source_playlists, &outputs, &items, &playlists, &args);
+            playlists.extend(rewritten.filenames);
+            unmapped = rewritten.unmapped;
+            errs.extend(rewritten.errs);
+        }
+        valid_filenames.extend(&playlists);
+
+        let (companions, companion_errs) = copy_companions(&outputs, &items, &args);
+        errs.extend(companion_errs);
+        valid_filenames.extend(&companions);
+
+        let (covers, cover_errs) = export_covers(&outputs, &items, &pics, &args, &progs);
+        errs.extend(cover_errs);
+        valid_filenames.extend(&covers);
+
+        if let Err(e) = evict(&args) {
+            errs.push(format!("cover art cache: {e}"));
+        }
+
+        if !skipped.is_empty() {
+            println!();
+            println!("SKIPPED FILES:");
+            for s in &skipped {
+                println!("{}", &s);
+            }
+        }
+
+        if !unmapped.is_empty() {
+            println!();
+            println!("UNMAPPED PLAYLIST ENTRIES:");
+            for entry in &unmapped {
+                println!("{}", &entry);
             }
         }
 
@@ -122,7 +291,25 @@ pub fn main() -> Result<(), Box<dyn Error>> {
         if args.dry_run {
             println!("DRY-RUN!");
         }
-        println!("Converted files: {}", valid_filenames.len());
+        println!(
+            "Converted files: {}",
+            valid_filenames.len() - playlists.len() - companions.len() - covers.len()
+        );
+        if !playlists.is_empty() {
+            println!("Playlists: {}", playlists.len());
+        }
+        if !companions.is_empty() {
+            println!("Companion files: {}", companions.len());
+        }
+        if !covers.is_empty() {
+            println!("Cover files: {}", covers.len());
+        }
+        if !skipped.is_empty() {
+            println!("Skipped files: {}", skipped.len());
+        }
+        if !unmapped.is_empty() {
+            println!("Unmapped playlist entries: {}", unmapped.len());
+        }
         println!("Errors occurred: {}", errs.len());
 
         if errs.is_empty() {
@@ -1,79 +1,497 @@
 // SPDX-License-Identifier: GPL-3.0-only
 // 🄯 2021, Alexey Parfenov <zxed@alkatrazstudio.net>
 
+use crate::args::AppArgs;
 use crate::convert::Item;
-use crate::cue::find_cue_info;
+use crate::cue::{CueFile, CueInfo, CueOptions, CueSegment, open_cue, parse_embedded_cue};
+use crate::filters::PathFilters;
+use crate::meta::{FileMeta, Meta, embedded_cue_sheet};
+use crate::playlist::{SourcePlaylist, is_playlist, read_playlist};
 use lexical_sort::natural_lexical_only_alnum_cmp;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::error::Error;
+use std::io::Read;
 use std::path::Component::{Normal, Prefix};
-use std::path::{Component, Path};
+use std::path::{Component, Path, PathBuf};
 
-pub fn find_files(dirs: &[String], exts: &[String]) -> Result<Vec<Item>, Box<dyn Error>> {
-    let mut items = Vec::new();
+pub struct WalkOptions {
+    pub follow_symlinks: bool,
+    pub skip_hidden: bool,
+    pub max_depth: Option<usize>,
+}
+
+struct Walker<'a> {
+    root: &'a Path,
+    exts: &'a [String],
+    filters: &'a PathFilters,
+    options: &'a WalkOptions,
+    // canonical paths of the visited directories and files,
+    // so that symlinks can't make a loop or yield the same file twice
+    visited: HashSet<PathBuf>,
+    files: InputFiles,
+}
+
+fn is_hidden(path: &Path) -> bool {
+    return path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.starts_with('.'));
+}
+
+impl Walker<'_> {
+    fn is_new(&mut self, path: &Path) -> bool {
+        if !self.options.follow_symlinks {
+            return true;
+        }
+        return path
+            .canonicalize()
+            .is_ok_and(|path| self.visited.insert(path));
+    }
+
+    fn add_file(&mut self, path: PathBuf) {
+        if !self
+            .filters
+            .is_match(path.strip_prefix(self.root).unwrap_or(&path))
+        {
+            return;
+        }
+
+        if let Some(ext) = path.extension()
+            && let Some(ext) = ext.to_str()
+        {
+            let ext = ext.to_lowercase();
+            if ext == "cue" {
+                if self.is_new(&path) {
+                    self.files.cue_files.push(path);
+                }
+            } else if is_playlist(&path) {
+                if self.is_new(&path) {
+                    let rel_path = path.strip_prefix(self.root).unwrap_or(&path).to_path_buf();
+                    self.files.playlists.push(SourcePlaylist { path, rel_path });
+                }
+            } else if self.exts.contains(&ext) && self.is_new(&path) {
+                self.files.audio_files.push(path);
+            }
+        }
+    }
+
+    fn walk(&mut self, dir: &Path, depth: usize) {
+        let Ok(entries) = dir.read_dir() else {
+            return;
+        };
+
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if self.options.skip_hidden && is_hidden(&path) {
+                continue;
+            }
+
+            let Ok(file_type) = entry.file_type() else {
+                continue;
+            };
+            let (is_dir, is_file) = if file_type.is_symlink() {
+                if !self.options.follow_symlinks {
+                    continue;
+                }
+                match std::fs::metadata(&path) {
+                    Ok(meta) => (meta.is_dir(), meta.is_file()),
+                    Err(_) => continue,
+                }
+            } else {
+                (file_type.is_dir(), file_type.is_file())
+            };
+
+            if is_dir {
+                if self.options.max_depth.is_some_and(|max| depth >= max) {
+                    continue;
+                }
+                if !self.is_new(&path) {
+                    println!("{}: skipped, already visited", path.display());
+                    continue;
+                }
+                self.walk(&path, depth + 1);
+            } else if is_file {
+                self.add_file(path);
+            }
+        }
+    }
+}
+
+fn new_item(filename: &str, cue: Option<CueInfo>) -> Option<Item> {
+    let basename = Path::new(filename).file_stem()?.to_str()?;
+    return Some(Item {
+        filename: filename.to_string(),
+        basename: basename.to_string(),
+        index: 0,
+        total: 0,
+        cue,
+    });
+}
+
+pub fn canonical(path: &Path) -> PathBuf {
+    return path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+}
+
+// x.cue or x.flac.cue for x.flac
+fn is_sidecar_cue(cue: &CueFile, audio_file: &Path) -> bool {
+    let cue_path = Path::new(&cue.filename);
+    return cue_path == audio_file.with_extension("cue")
+        || audio_file
+            .to_str()
+            .is_some_and(|filename| cue_path == Path::new(&(filename.to_string() + ".cue")));
+}
+
+fn has_cue_ext(path: &Path) -> bool {
+    return path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("cue"));
+}
+
+#[derive(Default)]
+pub struct InputFiles {
+    pub audio_files: Vec<PathBuf>,
+    pub cue_files: Vec<PathBuf>,
+    pub playlists: Vec<SourcePlaylist>,
+}
+
+fn walk_dirs(
+    dirs: &[String],
+    exts: &[String],
+    filters: &PathFilters,
+    options: &WalkOptions,
+) -> Result<InputFiles, Box<dyn Error>> {
+    let mut walker = Walker {
+        root: Path::new(""),
+        exts,
+        filters,
+        options,
+        visited: HashSet::new(),
+        files: InputFiles::default(),
+    };
 
     for dir in dirs {
         let input_dir = Path::new(dir);
         if !input_dir.exists() {
             return Err(format!("not found: {dir}").into());
         }
+        walker.root = input_dir;
+        if walker.is_new(input_dir) {
+            walker.walk(input_dir, 0);
+        }
+    }
 
-        if let Ok(entries) = input_dir.read_dir() {
-            for entry in entries.flatten() {
-                if let Ok(file_type) = entry.file_type() {
-                    let path = entry.path();
-                    if let Some(filename) = path.to_str() {
-                        if file_type.is_dir() {
-                            let sub_items = find_files(&[filename.to_owned()], exts)?;
-                            items.extend(sub_items);
-                        } else if file_type.is_file() {
-                            if let Some(ext) = path.extension()
-                                && let Some(ext) = ext.to_str()
-                            {
-                                let ext = ext.to_lowercase();
-                                let ext = ext.clone();
-                                if !exts.contains(&ext) {
-                                    continue;
-                                }
-                            }
-                            if let Some(basename) = path.file_stem()
-                                && let Some(basename) = basename.to_str()
-                            {
-                                let infos = find_cue_info(&path).unwrap_or_default();
-                                if infos.is_empty() {
-                                    items.push(Item {
-                                        filename: filename.to_string(),
-                                        basename: basename.to_string(),
-                                        index: 0,
-                                        total: 0,
-                                        cue: None,
-                                    });
-                                } else {
-                                    for info in infos {
-                                        items.push(Item {
-                                            filename: filename.to_string(),
-                                            basename: basename.to_string(),
-                                            index: 0,
-                                            total: 0,
-                                            cue: Some(info),
-                                        });
-                                    }
-                                }
-                            }
-                        }
-                    }
+    return Ok(walker.files);
+}
+
+// A list of files separated either by NUL or by newlines.
+// "-" means stdin.
+fn read_list(list: &str) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let mut buf = Vec::new();
+    if list == "-" {
+        std::io::stdin().read_to_end(&mut buf)?;
+    } else {
+        buf = std::fs::read(list)?;
+    }
+
+    let text = String::from_utf8_lossy(&buf);
+    let separator = if text.contains('\0') { '\0' } else { '\n' };
+    let paths = text
+        .split(separator)
+        .map(|line| line.trim_end_matches('\r'))
+        .filter(|line| !line.is_empty())
+        .map(PathBuf::from)
+        .collect();
+    return Ok(paths);
+}
+
+// Returns the audio files and the cue files that are listed in the input lists.
+// The cue sheets next to the listed audio files are also returned.
+fn read_lists(lists: &[String], filters: &PathFilters) -> Result<InputFiles, Box<dyn Error>> {
+    let mut files = InputFiles::default();
+
+    for list in lists {
+        let paths = if is_playlist(Path::new(list)) {
+            read_playlist(Path::new(list))?
+        } else {
+            read_list(list)?
+        };
+
+        for path in paths {
+            if !path.is_file() {
+                println!("{list}: not found: {}", path.display());
+                continue;
+            }
+            if !filters.is_match(&path) {
+                continue;
+            }
+
+            if has_cue_ext(&path) {
+                files.cue_files.push(path);
+            } else {
+                let mut cue_name = path.clone().into_os_string();
+                cue_name.push(".cue");
+                let sidecar_cues = [path.with_extension("cue"), PathBuf::from(cue_name)];
+                files
+                    .cue_files
+                    .extend(sidecar_cues.into_iter().filter(|cue| cue.is_file()));
+                files.audio_files.push(path);
+            }
+        }
+    }
+
+    return Ok(files);
+}
+
+fn dedup_files(files: &mut Vec<PathBuf>) {
+    let mut visited = HashSet::new();
+    files.retain(|path| visited.insert(canonical(path)));
+    files.sort();
+}
+
+// Returns the files of all input directories and input lists.
+// Only the playlists inside the input directories are returned.
+pub fn input_files(app_args: &AppArgs) -> Result<InputFiles, Box<dyn Error>> {
+    let mut files = walk_dirs(
+        &app_args.input_dirs,
+        &app_args.input_exts,
+        &app_args.path_filters,
+        &app_args.walk,
+    )?;
+    let list_files = read_lists(&app_args.input_lists, &app_args.path_filters)?;
+    files.audio_files.extend(list_files.audio_files);
+    files.cue_files.extend(list_files.cue_files);
+    dedup_files(&mut files.audio_files);
+    dedup_files(&mut files.cue_files);
+    files.playlists.sort_by(|a, b| a.path.cmp(&b.path));
+    return Ok(files);
+}
+
+pub fn find_files(
+    audio_files: Vec<PathBuf>,
+    cue_files: Vec<PathBuf>,
+    app_args: &AppArgs,
+) -> Vec<Item> {
+    let exts = &app_args.input_exts;
+    let cue_options = &app_args.cue;
+
+    let mut items = Vec::new();
+    let mut used_files = HashSet::new();
+    let mut unresolved_cues = Vec::new();
+    let mut sidecar_cues = HashSet::new();
+
+    for cue_file in cue_files {
+        let cue = match open_cue(&cue_file, cue_options.encoding) {
+            Ok(cue) => cue,
+            Err(e) => {
+                println!("{}: {}", cue_file.to_str().unwrap_or_default(), e);
+                continue;
+            }
+        };
+        if cue.has_decode_errors {
+            println!(
+                "{}: some characters can't be decoded as {}",
+                &cue.filename,
+                cue.encoding.name()
+            );
+        }
+
+        let filenames = match cue.resolve_files(exts) {
+            Ok(filenames) => filenames,
+            Err(e) => {
+                // a single FILE may still be matched to the audio file next to the cue sheet
+                if cue.files_count() == 1 {
+                    unresolved_cues.push(cue);
+                } else {
+                    println!("{}: {}", &cue.filename, e);
                 }
+                continue;
             }
+        };
+
+        if let Some(filename) = filenames
+            .iter()
+            .find(|filename| used_files.contains(&canonical(Path::new(filename))))
+        {
+            println!(
+                "{}: skipped, {} is already referenced by another cue sheet",
+                &cue.filename, filename
+            );
+            continue;
+        }
+
+        used_files.extend(
+            filenames
+                .iter()
+                .map(|filename| canonical(Path::new(filename))),
+        );
+        for (filename, info) in cue.tracks(&filenames, cue_options) {
+            items.extend(new_item(&filename, Some(info)));
         }
     }
 
-    items.sort_unstable_by(|a, b| natural_lexical_only_alnum_cmp(&a.basename, &b.basename));
+    for audio_file in audio_files {
+        let Some(filename) = audio_file.to_str() else {
+            continue;
+        };
+        if used_files.contains(&canonical(Path::new(filename))) {
+            continue;
+        }
+
+        // the name of the referenced file does not matter for a sidecar cue,
+        // e.g. the image may have been renamed
+        let cue = unresolved_cues
+            .iter()
+            .find(|cue| is_sidecar_cue(cue, &audio_file));
+        if let Some(cue) = cue {
+            sidecar_cues.insert(cue.filename.as_str());
+            for (filename, info) in cue.tracks(&[filename.to_string()], cue_options) {
+                items.extend(new_item(&filename, Some(info)));
+            }
+        } else {
+            items.extend(new_item(filename, None));
+        }
+    }
+
+    for cue in &unresolved_cues {
+        if !sidecar_cues.contains(cue.filename.as_str()) {
+            println!("{}: referenced file not found", &cue.filename);
+        }
+    }
+
+    items.sort_by(|a, b| natural_lexical_only_alnum_cmp(&a.basename, &b.basename));
     let n = items.len();
     for (i, item) in items.iter_mut().enumerate() {
         item.index = i;
         item.total = n;
     }
 
-    return Ok(items);
+    return items;
+}
+
+// Replaces every file that has a cue sheet in its tags with the tracks of that cue sheet.
+// A cue sheet on the disk takes precedence over the embedded one.
+pub fn expand_embedded_cues(
+    items: Vec<Item>,
+    metas: Vec<Result<Meta, String>>,
+    cue_options: &CueOptions,
+) -> (Vec<Item>, Vec<Result<Meta, String>>) {
+    let mut new_items = Vec::new();
+    let mut new_metas = Vec::new();
+
+    for (item, meta) in items.into_iter().zip(metas) {
+        let cue = match &meta {
+            Ok(meta) if item.cue.is_none() => embedded_cue_sheet(meta)
+                .and_then(|text| match parse_embedded_cue(&text, &item.filename) {
+                    Ok(cue) => Some(cue),
+                    Err(e) => {
+                        item.print_info("WARN", &format!("embedded cue sheet: {e}"));
+                        None
+                    }
+                })
+                .filter(|cue| cue.files_count() == 1),
+            _ => None,
+        };
+
+        let tracks = cue
+            .map(|cue| cue.tracks(std::slice::from_ref(&item.filename), cue_options))
+            .unwrap_or_default();
+        if tracks.is_empty() {
+            new_items.push(item);
+            new_metas.push(meta);
+            continue;
+        }
+
+        for (filename, info) in tracks {
+            if let Some(item) = new_item(&filename, Some(info)) {
+                new_items.push(item);
+                new_metas.push(meta.clone());
+            }
+        }
+    }
+
+    let n = new_items.len();
+    for (i, item) in new_items.iter_mut().enumerate() {
+        item.index = i;
+        item.total = n;
+    }
+
+    return (new_items, new_metas);
+}
+
+// Checks the tracks cut from the audio file by the same sample bounds as trim_filter.
+// Guarantees that no track starts or ends past the end of the audio,
+// and that the tracks do not overlap, i.e. the real lengths of the tracks
+// add up to the length of the audio without the gaps between the tracks
+// (the discarded pregaps and the hidden track before the first one).
+fn verify_samples(
+    segments: &[&CueSegment],
+    sample_rate: u32,
+    total_samples: u64,
+) -> Result<(), String> {
+    let mut segments = segments.to_vec();
+    segments.sort_by_key(|segment| segment.start_frame);
+
+    let mut tracks_len = 0;
+    let mut gaps_len = 0;
+    let mut pos = 0;
+    for segment in segments {
+        let start = segment.start_sample(sample_rate);
+        if start >= total_samples {
+            return Err(format!(
+                "the track at sample {start} starts past the end of the audio ({total_samples} samples)"
+            ));
+        }
+        let end = segment.end_sample(sample_rate).unwrap_or(total_samples);
+        if end > total_samples {
+            return Err(format!(
+                "the track at sample {start} ends at sample {end}, past the end of the audio ({total_samples} samples)"
+            ));
+        }
+        gaps_len += start.saturating_sub(pos);
+        tracks_len += end - start;
+        pos = pos.max(end);
+    }
+    gaps_len += total_samples - pos;
+
+    let expected_len = total_samples - gaps_len;
+    if tracks_len != expected_len {
+        return Err(format!(
+            "the tracks are {tracks_len} samples long in total, but the audio has {expected_len} samples outside of the gaps"
+        ));
+    }
+
+    return Ok(());
+}
+
+pub fn verify_cue_splits(items: &[Item], metas: &[Result<FileMeta, String>]) {
+    let mut file_segments = BTreeMap::<&str, Vec<&CueSegment>>::new();
+    let mut file_samples = HashMap::new();
+    for (item, meta) in items.iter().zip(metas) {
+        let Some(cue) = &item.cue else {
+            continue;
+        };
+        for segment in &cue.segments {
+            file_segments
+                .entry(segment.filename.as_str())
+                .or_default()
+                .push(segment);
+        }
+        if let Ok(meta) = meta
+            && let Some(sample_rate) = meta.sample_rate
+            && let Some(total_samples) = meta.total_samples
+        {
+            file_samples.insert(item.filename.as_str(), (item, sample_rate, total_samples));
+        }
+    }
+
+    for (filename, segments) in file_segments {
+        if let Some(&(item, sample_rate, total_samples)) = file_samples.get(filename)
+            && let Err(e) = verify_samples(&segments, sample_rate, total_samples)
+        {
+            item.print_info("WARN", &format!("{filename}: {e}"));
+        }
+    }
 }
 
 fn component_name(component: &Component) -> String {
@@ -121,3 +539,43 @@ pub fn print_tree(base_dir: &str, filenames: &[&String]) {
         prev_components.clone_from(&components);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // one sample per cue frame
+    const SAMPLE_RATE: u32 = 75;
+
+    fn verify(bounds: &[(u32, Option<u32>)], total_samples: u64) -> Result<(), String> {
+        let segments = bounds
+            .iter()
+            .map(|&(start_frame, end_frame)| CueSegment {
+                filename: "a.wav".to_string(),
+                start_frame,
+                end_frame,
+            })
+            .collect::<Vec<_>>();
+        let segments = segments.iter().collect::<Vec<_>>();
+        return verify_samples(&segments, SAMPLE_RATE, total_samples);
+    }
+
+    #[test]
+    fn split_tracks() {
+        assert!(verify(&[(0, Some(100)), (100, None)], 250).is_ok());
+        // the gaps are not a part of any track
+        assert!(verify(&[(10, Some(100)), (120, Some(200))], 250).is_ok());
+    }
+
+    #[test]
+    fn overlapping_tracks() {
+        assert!(verify(&[(0, Some(150)), (100, None)], 250).is_err());
+        assert!(verify(&[(0, None), (100, Some(200))], 250).is_err());
+    }
+
+    #[test]
+    fn tracks_past_the_end() {
+        assert!(verify(&[(0, Some(100)), (250, None)], 250).is_err());
+        assert!(verify(&[(0, Some(100)), (100, Some(300))], 250).is_err());
+    }
+}
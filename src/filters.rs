@@ -0,0 +1,223 @@
+// SPDX-License-Identifier: GPL-3.0-only
+// 🄯 2026, Alexey Parfenov <zxed@alkatrazstudio.net>
+
+use crate::album::Albums;
+use crate::convert::Item;
+use crate::meta::{FileMeta, MetaTags, tag_values};
+use globset::{GlobBuilder, GlobMatcher};
+use regex::Regex;
+use std::path::Path;
+
+#[derive(Clone)]
+pub enum PathPattern {
+    // a glob without a directory separator matches only the file name
+    Glob(GlobMatcher, bool),
+    Regex(Regex),
+}
+
+pub struct PathFilters {
+    pub include: Vec<PathPattern>,
+    pub exclude: Vec<PathPattern>,
+}
+
+#[derive(Clone)]
+enum TagOp {
+    Equal(String),
+    NotEqual(String),
+    Matches(Regex),
+}
+
+#[derive(Clone)]
+pub struct TagFilter {
+    name: String,
+    op: TagOp,
+}
+
+pub fn path_pattern(s: &str) -> Result<PathPattern, String> {
+    if let Some(rx) = s.strip_prefix("re:") {
+        let rx = Regex::new(rx).map_err(|e| e.to_string())?;
+        return Ok(PathPattern::Regex(rx));
+    }
+
+    let glob = GlobBuilder::new(s)
+        .case_insensitive(true)
+        .literal_separator(true)
+        .build()
+        .map_err(|e| e.to_string())?;
+    return Ok(PathPattern::Glob(glob.compile_matcher(), !s.contains('/')));
+}
+
+impl PathPattern {
+    // the path is relative to the input directory
+    pub fn is_match(&self, rel_path: &str) -> bool {
+        return match self {
+            Self::Glob(glob, true) => {
+                let name = Path::new(rel_path).file_name().unwrap_or_default();
+                glob.is_match(name)
+            }
+            Self::Glob(glob, false) => glob.is_match(rel_path),
+            Self::Regex(rx) => rx.is_match(rel_path),
+        };
+    }
+}
+
+impl PathFilters {
+    pub fn is_match(&self, rel_path: &Path) -> bool {
+        let rel_path = rel_path.to_string_lossy().replace('\\', "/");
+        if !self.include.is_empty() && !self.include.iter().any(|p| p.is_match(&rel_path)) {
+            return false;
+        }
+        return !self.exclude.iter().any(|p| p.is_match(&rel_path));
+    }
+}
+
+fn tag_names() -> Vec<String> {
+    return match serde_json::to_value(MetaTags::default()) {
+        Ok(serde_json::Value::Object(map)) => map.keys().cloned().collect(),
+        _ => Vec::new(),
+    };
+}
+
+// name=value, name!=value or name~regex
+pub fn tag_filter(s: &str) -> Result<TagFilter, String> {
+    let pos = s
+        .find(['=', '~'])
+        .ok_or_else(|| format!("expected NAME=VALUE, NAME!=VALUE or NAME~REGEX: {s}"))?;
+    let val = &s[pos + 1..];
+    let (name, op) = if s[pos..].starts_with('~') {
+        let rx = Regex::new(val).map_err(|e| e.to_string())?;
+        (&s[..pos], TagOp::Matches(rx))
+    } else if let Some(name) = s[..pos].strip_suffix('!') {
+        (name, TagOp::NotEqual(val.to_lowercase()))
+    } else {
+        (&s[..pos], TagOp::Equal(val.to_lowercase()))
+    };
+
+    let name = name.trim().to_lowercase();
+    if !tag_names().contains(&name) {
+        return Err(format!("unknown tag: {name}"));
+    }
+    return Ok(TagFilter { name, op });
+}
+
+impl TagFilter {
+    // a tag with multiple values matches if any of its values matches
+    fn is_match(&self, tags: &serde_json::Value) -> bool {
+        let val = tags
+            .get(&self.name)
+            .and_then(|val| val.as_str())
+            .unwrap_or_default();
+        let values = tag_values(val);
+        let has_value = |expected: &str| {
+            return values
+                .iter()
+                .any(|val| val.trim().to_lowercase() == expected);
+        };
+
+        return match &self.op {
+            TagOp::Equal(expected) => has_value(expected),
+            TagOp::NotEqual(expected) => !has_value(expected),
+            TagOp::Matches(rx) => values.iter().any(|val| rx.is_match(val)),
+        };
+    }
+}
+
+// Keeps only the items whose tags match all filters.
+// The items that failed to be probed are kept to report the errors.
+// The album tags must already be applied, so they can be filtered too.
+pub fn filter_by_tags(
+    items: Vec<Item>,
+    metas: Vec<Result<FileMeta, String>>,
+    albums: Albums,
+    filters: &[TagFilter],
+) -> (Vec<Item>, Vec<Result<FileMeta, String>>, Albums) {
+    if filters.is_empty() {
+        return (items, metas, albums);
+    }
+
+    let keep = metas
+        .iter()
+        .map(|meta| {
+            let Ok(meta) = meta else {
+                return true;
+            };
+            let tags = serde_json::to_value(&meta.tags).unwrap_or_default();
+            return filters.iter().all(|filter| filter.is_match(&tags));
+        })
+        .collect::<Vec<_>>();
+    let (mut items, metas): (Vec<Item>, Vec<Result<FileMeta, String>>) = items
+        .into_iter()
+        .zip(metas)
+        .zip(&keep)
+        .filter_map(|(pair, &k)| k.then_some(pair))
+        .unzip();
+    let albums = albums.retain(&keep);
+
+    let n = items.len();
+    for (i, item) in items.iter_mut().enumerate() {
+        item.index = i;
+        item.total = n;
+    }
+
+    return (items, metas, albums);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_match(filter: &str, tags: &MetaTags) -> bool {
+        let tags = serde_json::to_value(tags).unwrap();
+        return tag_filter(filter).unwrap().is_match(&tags);
+    }
+
+    #[test]
+    fn tag_filter_syntax() {
+        assert!(tag_filter(" Genre =Rock").is_ok());
+        assert!(tag_filter("genre~^(Rock|Pop)$").is_ok());
+        assert!(tag_filter("genre!=").is_ok());
+        assert_eq!(
+            tag_filter("genre").err().unwrap(),
+            "expected NAME=VALUE, NAME!=VALUE or NAME~REGEX: genre"
+        );
+        assert_eq!(
+            tag_filter("style=Rock").err().unwrap(),
+            "unknown tag: style"
+        );
+        assert!(tag_filter("genre~(").is_err());
+    }
+
+    #[test]
+    fn tag_filter_values() {
+        let tags = MetaTags {
+            genre: "Rock\0Jazz Fusion".to_string(),
+            album: "Some Album".to_string(),
+            ..Default::default()
+        };
+        // case-insensitive and any of the multiple values
+        assert!(is_match("genre=rock", &tags));
+        assert!(is_match("genre=JAZZ FUSION", &tags));
+        assert!(!is_match("genre=jazz", &tags));
+        assert!(!is_match("genre=Rock; Jazz Fusion", &tags));
+
+        // not equal to any of the values
+        assert!(is_match("genre!=Pop", &tags));
+        assert!(!is_match("genre!=jazz fusion", &tags));
+
+        // the regex is matched against every value as is
+        assert!(is_match("genre~^Jazz", &tags));
+        assert!(!is_match("genre~^jazz", &tags));
+        assert!(!is_match("genre~Rock.Jazz", &tags));
+
+        // the value may contain the operators
+        assert!(is_match("album~Some\\s", &tags));
+        assert!(is_match("album!=Some=Album", &tags));
+    }
+
+    #[test]
+    fn tag_filter_missing_tag() {
+        let tags = MetaTags::default();
+        assert!(is_match("genre!=Rock", &tags));
+        assert!(!is_match("genre~.*", &tags));
+    }
+}
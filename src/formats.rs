@@ -6,6 +6,36 @@ pub enum Format {
     Ogg,
 }
 
+// what to do with the sources that are already encoded with the output codec
+pub enum SameFormatPolicy {
+    Transcode,
+    CopyStream,
+    CopyFile,
+    BitrateCap(u32),
+}
+
+// what to do when a lossy source needs to be transcoded into a lossy format
+#[derive(PartialEq, Eq)]
+pub enum LossyPolicy {
+    Allow,
+    Warn,
+    Skip,
+    Error,
+}
+
+const LOSSY_CODECS: [&str; 4] = ["mp3", "aac", "vorbis", "opus"];
+
+pub fn is_lossy_codec(codec: &str) -> bool {
+    return LOSSY_CODECS.contains(&codec);
+}
+
+#[derive(PartialEq, Eq)]
+pub enum CopyMode {
+    None,
+    Stream,
+    File,
+}
+
 impl Format {
     pub const MIN_QUALITY: u8 = 1;
     pub const MAX_QUALITY: u8 = 100;
@@ -20,6 +50,31 @@ impl Format {
         .collect();
     }
 
+    pub fn copy_args(&self) -> Vec<String> {
+        return match self {
+            Self::MP3 => vec!["-c:a", "copy", "-write_id3v2", "1", "-id3v2_version", "4"],
+            Self::Ogg => vec!["-c:a", "copy"],
+        }
+        .iter()
+        .map(|s| (*s).to_string())
+        .collect();
+    }
+
+    // ID3v2 has a dedicated frame for the ISRC
+    pub const fn isrc_tag(&self) -> &'static str {
+        return match self {
+            Self::MP3 => "TSRC",
+            Self::Ogg => "ISRC",
+        };
+    }
+
+    pub const fn codec_name(&self) -> &'static str {
+        return match self {
+            Self::MP3 => "mp3",
+            Self::Ogg => "vorbis",
+        };
+    }
+
     pub fn normalize_pic_quality(&self, quality: u8) -> u8 {
         let in_range = Self::MAX_QUALITY - Self::MIN_QUALITY;
 
@@ -0,0 +1,54 @@
+// SPDX-License-Identifier: GPL-3.0-only
+// 🄯 2026, Alexey Parfenov <zxed@alkatrazstudio.net>
+
+use crate::args::AppArgs;
+use crate::convert::Progs;
+use crate::cue::open_cue;
+use crate::files::input_files;
+use crate::meta::{probe_file, probed_duration};
+use std::error::Error;
+
+pub fn lint(args: &AppArgs, progs: &Progs) -> Result<(), Box<dyn Error>> {
+    let cue_files = input_files(args)?.cue_files;
+    let mut problems_count = 0;
+
+    for cue_file in &cue_files {
+        let cue_filename = cue_file.to_str().unwrap_or_default();
+
+        let problems = match open_cue(cue_file, args.cue.encoding) {
+            Ok(cue) => {
+                let filenames = cue.find_files(&args.input_exts);
+                let durations = filenames
+                    .iter()
+                    .map(|filename| {
+                        let meta = probe_file(filename.as_ref()?, &progs.ffprobe_bin).ok()?;
+                        return probed_duration(&meta);
+                    })
+                    .collect::<Vec<_>>();
+                cue.problems(&filenames, &durations)
+            }
+            Err(e) => vec![e.to_string()],
+        };
+
+        if problems.is_empty() {
+            println!("[OK] {cue_filename}");
+        } else {
+            println!("[ERR] {cue_filename}");
+            for problem in &problems {
+                println!("  {problem}");
+            }
+        }
+        problems_count += problems.len();
+    }
+
+    println!();
+    println!("Checked cue-sheets: {}", cue_files.len());
+    println!("Problems found: {problems_count}");
+
+    if problems_count == 0 {
+        return Ok(());
+    }
+
+    println!();
+    return Err("Some problems found".into());
+}
@@ -0,0 +1,362 @@
+// SPDX-License-Identifier: GPL-3.0-only
+// 🄯 2026, Alexey Parfenov <zxed@alkatrazstudio.net>
+
+use crate::album::Albums;
+use crate::args::AppArgs;
+use crate::convert::{Item, Progs, input_args, trim_filter};
+use crate::meta::FileMeta;
+use crate::replaygain::ReplayGainMode;
+use rayon::prelude::*;
+use serde::Deserialize;
+use std::error::Error;
+use std::process::Command;
+
+#[derive(PartialEq, Eq)]
+pub enum LoudnessNormMode {
+    Off,
+    Track,
+    Album,
+}
+
+pub struct LoudnessTarget {
+    pub integrated: f64,
+    pub true_peak: f64,
+    pub lra: f64,
+}
+
+#[derive(Deserialize)]
+struct LoudnormStats {
+    input_i: String,
+    input_tp: String,
+    input_lra: String,
+    input_thresh: String,
+    target_offset: String,
+}
+
+#[derive(Clone)]
+pub struct Loudness {
+    pub integrated: f64,
+    pub true_peak: f64,
+    pub duration: f64,
+}
+
+#[derive(Clone)]
+pub struct TrackLoudness {
+    pub loudness: Loudness,
+    pub lra: f64,
+    pub threshold: f64,
+    pub offset: f64,
+}
+
+fn parse_stat(s: &str) -> Result<f64, Box<dyn Error>> {
+    let val = s.trim().parse::<f64>()?;
+    return Ok(val);
+}
+
+// loudnorm prints its stats as a JSON object at the very end of the output
+fn parse_loudnorm_output(output: &str) -> Result<LoudnormStats, Box<dyn Error>> {
+    let start = output
+        .rfind('{')
+        .ok_or("no loudness stats in the ffmpeg output")?;
+    let end = output
+        .rfind('}')
+        .ok_or("no loudness stats in the ffmpeg output")?;
+    if end < start {
+        return Err("malformed loudness stats in the ffmpeg output".into());
+    }
+    let stats: LoudnormStats = serde_json::from_str(&output[start..=end])?;
+    return Ok(stats);
+}
+
+fn target_params(target: &LoudnessTarget) -> String {
+    return format!(
+        "I={}:TP={}:LRA={}",
+        target.integrated, target.true_peak, target.lra
+    );
+}
+
+fn measure_args(
+    input_args: Vec<String>,
+    trim_filter: Option<String>,
+    target: &LoudnessTarget,
+) -> Vec<String> {
+    let mut filters = Vec::from_iter(trim_filter);
+    filters.push(format!(
+        "loudnorm={}:print_format=json",
+        target_params(target)
+    ));
+
+    let mut args = str_vec!["-hide_banner", "-nostats", "-loglevel", "info"];
+    args.extend(input_args);
+    args.extend(str_vec![
+        "-map",
+        "0:a",
+        "-af",
+        &filters.join(","),
+        "-f",
+        "null",
+        "-"
+    ]);
+    return args;
+}
+
+fn measure_loudness(
+    args: &[String],
+    duration: f64,
+    progs: &Progs,
+) -> Result<TrackLoudness, Box<dyn Error>> {
+    let output = Command::new(&progs.ffmpeg_bin).args(args).output()?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if output.status.code().ok_or("Cannot get the exit code")? != 0 {
+        return Err(stderr.into());
+    }
+
+    let stats = parse_loudnorm_output(&stderr)?;
+    return Ok(TrackLoudness {
+        loudness: Loudness {
+            integrated: parse_stat(&stats.input_i)?,
+            true_peak: parse_stat(&stats.input_tp)?,
+            duration,
+        },
+        lra: parse_stat(&stats.input_lra)?,
+        threshold: parse_stat(&stats.input_thresh)?,
+        offset: parse_stat(&stats.target_offset)?,
+    });
+}
+
+// The album loudness is approximated by averaging the energy of all tracks,
+// weighted by their duration.
+// The album peak is the maximum of all track peaks.
+pub fn album_loudness(tracks: &[&Loudness]) -> Option<Loudness> {
+    let total_duration = tracks.iter().map(|t| t.duration).sum::<f64>();
+    if total_duration <= 0.0 {
+        return None;
+    }
+
+    let energy = tracks
+        .iter()
+        .map(|t| t.duration * 10_f64.powf(t.integrated / 10.0))
+        .sum::<f64>();
+
+    return Some(Loudness {
+        integrated: 10.0 * (energy / total_duration).log10(),
+        true_peak: tracks
+            .iter()
+            .map(|t| t.true_peak)
+            .fold(f64::NEG_INFINITY, f64::max),
+        duration: total_duration,
+    });
+}
+
+fn measure_item(
+    item: &Item,
+    meta: &FileMeta,
+    app_args: &AppArgs,
+    progs: &Progs,
+) -> Result<Option<TrackLoudness>, Box<dyn Error>> {
+    let trim = trim_filter(item, meta);
+    let (input_args, _concat_list) = input_args(item, app_args.dry_run, trim.as_ref())?;
+    let args = measure_args(
+        input_args,
+        trim.map(|trim| trim.filter),
+        &app_args.loudness_target,
+    );
+    item.print_args(&progs.ffmpeg_bin, &args);
+    if app_args.dry_run {
+        return Ok(None);
+    }
+    let loudness = measure_loudness(&args, meta.duration.unwrap_or_default(), progs)?;
+    return Ok(Some(loudness));
+}
+
+pub fn measure_items(
+    items: &[Item],
+    metas: &mut [Result<FileMeta, String>],
+    albums: &Albums,
+    app_args: &AppArgs,
+    progs: &Progs,
+) {
+    if app_args.replaygain == ReplayGainMode::Off && app_args.loudness_norm == LoudnessNormMode::Off
+    {
+        return;
+    }
+
+    // the skipped files are not converted, so they don't belong to the album loudness either
+    let measured = metas
+        .iter()
+        .map(|meta| meta.as_ref().is_ok_and(|meta| meta.skip_reason.is_none()))
+        .collect::<Vec<bool>>();
+
+    let results = items
+        .par_iter()
+        .zip(metas.par_iter())
+        .zip(&measured)
+        .map(|((item, meta), &measured)| {
+            if !measured {
+                return None;
+            }
+            let meta = meta.as_ref().ok()?;
+            return measure_item(item, meta, app_args, progs)
+                .map_err(|e| e.to_string())
+                .transpose();
+        })
+        .collect::<Vec<_>>();
+
+    for (i, result) in results.into_iter().enumerate() {
+        match result {
+            Some(Ok(loudness)) => {
+                if let Ok(meta) = &mut metas[i] {
+                    meta.loudness = Some(loudness);
+                }
+            }
+            Some(Err(e)) => metas[i] = Err(e),
+            None => {}
+        }
+    }
+
+    if app_args.dry_run {
+        return;
+    }
+
+    for album in &albums.list {
+        let album_items = album
+            .items
+            .iter()
+            .copied()
+            .filter(|&i| measured[i])
+            .collect::<Vec<usize>>();
+        let tracks = album_items
+            .iter()
+            .filter_map(|&i| metas[i].as_ref().ok()?.loudness.as_ref())
+            .map(|track| &track.loudness)
+            .collect::<Vec<&Loudness>>();
+        let album_loudness = if tracks.len() == album_items.len() {
+            album_loudness(&tracks)
+        } else {
+            None
+        };
+
+        match album_loudness {
+            Some(album_loudness) => {
+                for &i in &album_items {
+                    if let Ok(meta) = &mut metas[i] {
+                        meta.album_loudness = Some(album_loudness.clone());
+                    }
+                }
+            }
+
+            // the rest of the album can't be normalized with the same gain
+            None if app_args.loudness_norm == LoudnessNormMode::Album => {
+                for &i in &album_items {
+                    if metas[i].is_ok() {
+                        metas[i] = Err("can't measure the album loudness".to_string());
+                    }
+                }
+            }
+
+            None => {}
+        }
+    }
+}
+
+// A single gain for the whole album preserves the loudness differences between its tracks.
+// The gain is limited so that the loudest peak of the album does not exceed the target.
+fn album_gain(album: &Loudness, target: &LoudnessTarget) -> f64 {
+    return (target.integrated - album.integrated).min(target.true_peak - album.true_peak);
+}
+
+fn shifted(loudness: &Loudness, gain: f64) -> Loudness {
+    return Loudness {
+        integrated: loudness.integrated + gain,
+        true_peak: loudness.true_peak + gain,
+        duration: loudness.duration,
+    };
+}
+
+// Predicts the loudness of the output track and album after the normalization.
+pub fn output_loudness(
+    meta: &FileMeta,
+    app_args: &AppArgs,
+) -> (Option<Loudness>, Option<Loudness>) {
+    let track = meta.loudness.as_ref().map(|track| &track.loudness);
+    let album = meta.album_loudness.as_ref();
+    let target = &app_args.loudness_target;
+
+    return match app_args.loudness_norm {
+        LoudnessNormMode::Off => (track.cloned(), album.cloned()),
+
+        LoudnessNormMode::Track => {
+            let normalized = |loudness: &Loudness| Loudness {
+                integrated: target.integrated,
+                true_peak: (loudness.true_peak + target.integrated - loudness.integrated)
+                    .min(target.true_peak),
+                duration: loudness.duration,
+            };
+            (track.map(normalized), album.map(normalized))
+        }
+
+        LoudnessNormMode::Album => match album {
+            Some(album) => {
+                let gain = album_gain(album, target);
+                (
+                    track.map(|track| shifted(track, gain)),
+                    Some(shifted(album, gain)),
+                )
+            }
+            None => (None, None),
+        },
+    };
+}
+
+// loudnorm upsamples to 192 kHz, so the sample rate needs to be set explicitly after it
+pub fn norm_changes_sample_rate(filters: &[String], app_args: &AppArgs) -> bool {
+    return app_args.loudness_norm == LoudnessNormMode::Track && !filters.is_empty();
+}
+
+pub fn norm_filters(meta: &FileMeta, app_args: &AppArgs) -> Vec<String> {
+    let target = &app_args.loudness_target;
+
+    return match app_args.loudness_norm {
+        LoudnessNormMode::Off => Vec::new(),
+
+        LoudnessNormMode::Track => {
+            let measured = if let Some(track) = &meta.loudness {
+                if !track.loudness.integrated.is_finite() {
+                    return Vec::new();
+                }
+                format!(
+                    "measured_I={}:measured_TP={}:measured_LRA={}:measured_thresh={}:offset={}",
+                    track.loudness.integrated,
+                    track.loudness.true_peak,
+                    track.lra,
+                    track.threshold,
+                    track.offset
+                )
+            } else if app_args.dry_run {
+                "measured_I=<measured>:measured_TP=<measured>:measured_LRA=<measured>:measured_thresh=<measured>:offset=<measured>".to_string()
+            } else {
+                return Vec::new();
+            };
+
+            vec![format!(
+                "loudnorm={}:{}:linear=true",
+                target_params(target),
+                measured
+            )]
+        }
+
+        LoudnessNormMode::Album => {
+            if let Some(album) = &meta.album_loudness {
+                let gain = album_gain(album, target);
+                if !gain.is_finite() {
+                    return Vec::new();
+                }
+                vec![format!("volume={gain:.2}dB")]
+            } else if app_args.dry_run {
+                vec!["volume=<measured>dB".to_string()]
+            } else {
+                Vec::new()
+            }
+        }
+    };
+}
@@ -19,15 +19,30 @@
     clippy::option_if_let_else,
 )]
 
+macro_rules! str_vec {
+    ($($x:expr),*) => (vec![$($x.to_string()),*]);
+}
+
+mod album;
 mod args;
+mod companions;
 mod concurrent_map;
 mod convert;
 mod cue;
 mod entry;
 mod files;
+mod filters;
 mod formats;
+mod lint;
+mod loudness;
+mod merge;
 mod meta;
+mod pic_cache;
 mod pics;
+mod playlist;
+mod replaygain;
+mod resample;
+mod tags;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     return entry::main();
@@ -0,0 +1,328 @@
+// SPDX-License-Identifier: GPL-3.0-only
+// 🄯 2026, Alexey Parfenov <zxed@alkatrazstudio.net>
+
+use crate::album::Albums;
+use crate::args::AppArgs;
+use crate::convert::{
+    ConcatList, Item, Progs, add_meta, input_args, render_template, sanitize_filename, trim_filter,
+};
+use crate::cue::{CueIndex, CueSheet, CueTrack, cue_quoted, secs_to_frames};
+use crate::meta::{FileMeta, join_values, prepare_filename_tags};
+use rayon::prelude::*;
+use std::error::Error;
+use std::path::Path;
+use std::process::Command;
+
+// All tracks are decoded and joined by the concat filter,
+// which also converts them to a common sample rate and channel layout.
+fn merge_args(
+    tracks: &[(&Item, &FileMeta)],
+    dry_run: bool,
+) -> Result<(Vec<String>, Vec<ConcatList>), Box<dyn Error>> {
+    let mut args = Vec::new();
+    let mut lists = Vec::new();
+    let mut filters = Vec::new();
+    let mut concat_inputs = Vec::new();
+
+    for (i, (item, meta)) in tracks.iter().enumerate() {
+        let trim = trim_filter(item, meta);
+        let (item_args, list) = input_args(item, dry_run, trim.as_ref())?;
+        args.extend(item_args);
+        lists.extend(list);
+
+        if let Some(trim) = trim {
+            filters.push(format!("[{i}:a]{}[a{i}]", trim.filter));
+            concat_inputs.push(format!("[a{i}]"));
+        } else {
+            concat_inputs.push(format!("[{i}:a]"));
+        }
+    }
+    filters.push(format!(
+        "{}concat=n={}:v=0:a=1[out]",
+        concat_inputs.concat(),
+        tracks.len()
+    ));
+
+    args.extend(str_vec![
+        "-filter_complex",
+        &filters.join(";"),
+        "-map",
+        "[out]",
+        "-map_metadata",
+        "-1",
+        "-c:a",
+        "flac"
+    ]);
+    return Ok((args, lists));
+}
+
+// The exact duration of the track in seconds.
+// The sample count of a cue track is the one between its trim bounds.
+fn track_duration(item: &Item, meta: &FileMeta) -> Option<f64> {
+    let samples = meta
+        .sample_rate
+        .zip(meta.total_samples)
+        .and_then(|(rate, total)| {
+            let Some(cue) = &item.cue else {
+                return Some(total);
+            };
+            let [segment] = cue.segments.as_slice() else {
+                return None;
+            };
+            let end = segment.end_sample(rate).unwrap_or(total).min(total);
+            return Some(end.saturating_sub(segment.start_sample(rate)));
+        });
+    #[allow(clippy::cast_precision_loss)]
+    return match (samples, meta.sample_rate) {
+        (Some(samples), Some(rate)) => Some(samples as f64 / f64::from(rate)),
+        _ => meta.duration,
+    };
+}
+
+// A cue sheet for the tracks merged into a single image one after another.
+// Each track is specified by its meta and its duration in seconds.
+fn merged_cue(image_name: &str, tracks: &[(&FileMeta, f64)]) -> Result<String, Box<dyn Error>> {
+    let (first_meta, _) = tracks.first().ok_or("no tracks to merge")?;
+    let album = &first_meta.tags;
+    let val = |s: &str| join_values(s, "; ");
+
+    let mut sheet = CueSheet {
+        files: vec![image_name.to_string()],
+        title: val(&album.album),
+        performer: val(&album.album_artist_or_va),
+        ..Default::default()
+    };
+    if album.catalog_number.len() == 13 && album.catalog_number.bytes().all(|c| c.is_ascii_digit())
+    {
+        sheet.catalog.clone_from(&album.catalog_number);
+    }
+    if !album.genre.is_empty() {
+        sheet
+            .comments
+            .push(format!("GENRE {}", cue_quoted(&val(&album.genre))));
+    }
+    if !album.date.is_empty() {
+        sheet.comments.push(format!("DATE {}", album.date));
+    }
+
+    let mut start = 0.0;
+    for (i, (meta, duration)) in tracks.iter().enumerate() {
+        let tags = &meta.tags;
+        let number = u8::try_from(i + 1)
+            .ok()
+            .filter(|&number| number <= 99)
+            .ok_or("a cue sheet can't have more than 99 tracks")?;
+
+        // the artist tag of a compilation track is replaced with the album artist
+        let artist = if meta.track_artist.trim().is_empty() {
+            &tags.artist
+        } else {
+            &meta.track_artist
+        };
+
+        let isrc = if tags.isrc.len() == 12 && tags.isrc.bytes().all(|c| c.is_ascii_alphanumeric())
+        {
+            tags.isrc.clone()
+        } else {
+            String::new()
+        };
+
+        sheet.tracks.push(CueTrack {
+            number,
+            title: val(&tags.title),
+            performer: val(artist),
+            songwriter: val(&tags.composer),
+            isrc,
+            comments: Vec::new(),
+            indexes: vec![CueIndex {
+                number: 1,
+                file: 0,
+                frames: secs_to_frames(start),
+            }],
+        });
+        start += duration;
+    }
+
+    return Ok(sheet.render());
+}
+
+fn merge_album(
+    tracks: &[(&Item, &FileMeta)],
+    merge_template: &str,
+    app_args: &AppArgs,
+    progs: &Progs,
+) -> Result<String, Box<dyn Error>> {
+    let &(first_item, first_meta) = tracks.first().ok_or("no tracks to merge")?;
+    let tags = &first_meta.tags;
+    first_item.print_info(
+        "INFO",
+        &format!("merging {} tracks of {}", tracks.len(), &tags.album),
+    );
+
+    let filename_tags = prepare_filename_tags(tags, app_args.min_track_number_digits);
+    let filename = render_template(merge_template, &filename_tags, &app_args.values_separator)?;
+    let output_filename = sanitize_filename(&(filename + ".flac"))?;
+
+    let output_path = Path::new(&app_args.output_dir).join(output_filename);
+    let output_path_str = output_path.to_str().ok_or("Can't convert path to string")?;
+    let cue_path = output_path.with_extension("cue");
+    let cue_path_str = cue_path.to_str().ok_or("Can't convert path to string")?;
+    let dir_path = output_path
+        .parent()
+        .ok_or_else(|| format!("no parent for {output_path_str}"))?;
+    let image_name = output_path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or("Can't convert path to string")?;
+
+    if !app_args.overwrite {
+        for path in [&output_path, &cue_path] {
+            if path.exists() {
+                return Err(format!("file exists: {}", path.display()).into());
+            }
+        }
+    }
+
+    let cue_tracks = tracks
+        .iter()
+        .map(|(item, meta)| {
+            let duration = track_duration(item, meta)
+                .ok_or_else(|| format!("unknown duration: {}", item.filename))?;
+            return Ok((*meta, duration));
+        })
+        .collect::<Result<Vec<(&FileMeta, f64)>, String>>()?;
+    let cue = merged_cue(image_name, &cue_tracks)?;
+
+    let mut args = str_vec!["-hide_banner", "-nostats", "-loglevel", "warning", "-y"];
+    let (merge_args, _concat_lists) = merge_args(tracks, app_args.dry_run)?;
+    args.extend(merge_args);
+
+    add_meta(&mut args, &tags.album, "album");
+    add_meta(&mut args, &tags.album_artist_or_va, "artist");
+    add_meta(&mut args, &tags.genre, "genre");
+    add_meta(&mut args, &tags.date, "date");
+    add_meta(&mut args, &tags.catalog_number, "CATALOGNUMBER");
+    add_meta(
+        &mut args,
+        &first_meta.replaygain.album_gain,
+        "REPLAYGAIN_ALBUM_GAIN",
+    );
+    add_meta(
+        &mut args,
+        &first_meta.replaygain.album_peak,
+        "REPLAYGAIN_ALBUM_PEAK",
+    );
+    args.push(output_path_str.to_string());
+
+    first_item.print_args(&progs.ffmpeg_bin, &args);
+    first_item.print_info("CUE", cue_path_str);
+    if app_args.dry_run {
+        return Ok(output_path_str.into());
+    }
+
+    std::fs::create_dir_all(dir_path)?;
+    let output = Command::new(&progs.ffmpeg_bin).args(args).output()?;
+    if output.status.code().ok_or("Cannot get the exit code")? != 0 {
+        return Err(std::str::from_utf8(&output.stderr)?.into());
+    }
+    std::fs::write(&cue_path, cue)?;
+
+    return Ok(output_path_str.into());
+}
+
+// Returns the merge result for every album.
+// An album is not merged if any of its tracks has failed.
+pub fn merge_albums(
+    items: &[Item],
+    metas: &[Result<FileMeta, String>],
+    albums: &Albums,
+    merge_template: &str,
+    app_args: &AppArgs,
+    progs: &Progs,
+) -> Vec<Result<String, String>> {
+    return albums
+        .list
+        .par_iter()
+        .map(|album| {
+            let tracks = album
+                .items
+                .iter()
+                .filter_map(|&i| Some((&items[i], metas[i].as_ref().ok()?)))
+                .collect::<Vec<_>>();
+            let result = if tracks.len() == album.items.len() {
+                merge_album(&tracks, merge_template, app_args, progs).map_err(|e| e.to_string())
+            } else {
+                Err(format!(
+                    "{} of {} tracks of the album have failed",
+                    album.items.len() - tracks.len(),
+                    album.items.len()
+                ))
+            };
+            if let Err(e) = &result
+                && let Some(&i) = album.items.first()
+            {
+                items[i].print_info("ERR", e);
+            }
+            return result;
+        })
+        .collect();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(title: &str, track_artist: &str, total_samples: u64) -> (Item, FileMeta) {
+        let item = Item {
+            filename: format!("{title}.flac"),
+            basename: title.to_string(),
+            index: 0,
+            total: 0,
+            cue: None,
+        };
+        let mut meta = FileMeta {
+            track_artist: track_artist.to_string(),
+            sample_rate: Some(44100),
+            total_samples: Some(total_samples),
+            // the container duration is less precise than the sample count
+            duration: Some(61.0),
+            ..Default::default()
+        };
+        meta.tags.title = title.to_string();
+        meta.tags.album = "Album".to_string();
+        meta.tags.artist = "Various Artists".to_string();
+        meta.tags.album_artist_or_va = "Various Artists".to_string();
+        return (item, meta);
+    }
+
+    #[test]
+    fn merged_compilation_cue() {
+        // one minute and one cue frame
+        let tracks = [
+            track("One", "Artist A", 44100 * 60 + 588),
+            track("Two", "", 44100 * 30),
+        ];
+        let cue_tracks = tracks
+            .iter()
+            .map(|(item, meta)| (meta, track_duration(item, meta).unwrap()))
+            .collect::<Vec<_>>();
+        let cue = merged_cue("Album.flac", &cue_tracks).unwrap();
+
+        assert_eq!(
+            cue.lines().collect::<Vec<_>>(),
+            vec![
+                "PERFORMER \"Various Artists\"",
+                "TITLE \"Album\"",
+                "FILE \"Album.flac\" WAVE",
+                "  TRACK 01 AUDIO",
+                "    TITLE \"One\"",
+                "    PERFORMER \"Artist A\"",
+                "    INDEX 01 00:00:00",
+                "  TRACK 02 AUDIO",
+                "    TITLE \"Two\"",
+                "    PERFORMER \"Various Artists\"",
+                "    INDEX 01 01:00:01",
+            ]
+        );
+    }
+}
@@ -2,6 +2,8 @@
 // 🄯 2021, Alexey Parfenov <zxed@alkatrazstudio.net>
 
 use crate::cue::CueInfo;
+use crate::loudness::{Loudness, TrackLoudness};
+use crate::replaygain::ReplayGain;
 use regex::Regex;
 use sanitize_filename::{Options, sanitize_with_options};
 use serde::{Deserialize, Serialize};
@@ -14,14 +16,24 @@ use std::path::Path;
 use std::process::Command;
 use std::sync::LazyLock;
 
-#[derive(Serialize, Deserialize)]
+// separates multiple values inside a single MetaTags field
+pub const VALUES_SEPARATOR: char = '\0';
+
+#[derive(Serialize, Deserialize, Clone)]
 pub struct MetaStreamTags {
     comment: Option<String>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct MetaStream {
     codec_type: String,
+    codec_name: Option<String>,
+    bit_rate: Option<String>,
+    sample_rate: Option<String>,
+    channels: Option<u32>,
+    duration: Option<String>,
+    duration_ts: Option<u64>,
+    time_base: Option<String>,
     width: Option<u32>,
     height: Option<u32>,
     tags: Option<MetaStreamTags>,
@@ -32,15 +44,18 @@ impl MetaStreamTags {
 }
 
 impl MetaStream {
+    const AUDIO: &'static str = "audio";
     const VIDEO: &'static str = "video";
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct MetaFormat {
+    duration: Option<String>,
+    bit_rate: Option<String>,
     tags: Option<HashMap<String, Value>>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Meta {
     streams: Vec<MetaStream>,
     format: MetaFormat,
@@ -52,6 +67,7 @@ pub struct MetaTags {
     pub album: String,
     pub artist: String,
     pub catalog_number: String,
+    pub isrc: String,
     pub author: String,
     pub comment: String,
     pub composer: String,
@@ -72,6 +88,8 @@ pub struct MetaTags {
     pub dir_name: String,
     pub file_base: String,
     pub file_ext: String,
+    pub is_compilation: String,
+    pub album_artist_or_va: String,
 }
 
 #[derive(Default)]
@@ -80,6 +98,19 @@ pub struct FileMeta {
     pub pic_width: u32,
     pub pic_height: u32,
     pub tags: MetaTags,
+    pub album_artist: String,
+    pub track_artist: String,
+    pub compilation: bool,
+    pub duration: Option<f64>,
+    pub sample_rate: Option<u32>,
+    pub channels: Option<u32>,
+    pub total_samples: Option<u64>,
+    pub codec: String,
+    pub bit_rate: Option<u64>,
+    pub replaygain: ReplayGain,
+    pub loudness: Option<TrackLoudness>,
+    pub album_loudness: Option<Loudness>,
+    pub skip_reason: Option<String>,
 }
 
 fn to_str(x: Option<&OsStr>) -> String {
@@ -95,15 +126,38 @@ fn first_val(map: &HashMap<String, String>, keys: &[&str]) -> String {
     return String::default();
 }
 
-fn fill_tags(hash: &HashMap<String, Value>, filename: &str, cue: Option<&CueInfo>) -> MetaTags {
+fn split_values(s: &str) -> String {
+    static RX_SEP: LazyLock<Regex> =
+        LazyLock::new(|| Regex::new(r"\s*(?:;|\x00|\s/\s)\s*").unwrap());
+
+    return RX_SEP
+        .split(s)
+        .filter(|val| !val.is_empty())
+        .collect::<Vec<&str>>()
+        .join(&VALUES_SEPARATOR.to_string());
+}
+
+pub fn tag_values(s: &str) -> Vec<String> {
+    return s
+        .split(VALUES_SEPARATOR)
+        .filter(|val| !val.is_empty())
+        .map(|val| val.to_string())
+        .collect();
+}
+
+pub fn join_values(s: &str, separator: &str) -> String {
+    return tag_values(s).join(separator);
+}
+
+fn is_true_val(s: &str) -> bool {
+    return matches!(s.to_lowercase().as_str(), "1" | "y" | "yes" | "true");
+}
+
+fn normalize_tags(hash: &HashMap<String, Value>) -> HashMap<String, String> {
     static RX_ALPHA: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"[^a-z]").unwrap());
-    static RX_TRACK: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^(\d+)/(\d+)$").unwrap());
 
     let mut tags = HashMap::new();
 
-    let file_path = Path::new(filename).canonicalize().unwrap();
-    let dir_path = file_path.parent().unwrap();
-
     let mut keys = hash.keys().collect::<Vec<_>>();
     keys.sort_by(|a, b| {
         let ord = a.to_lowercase().cmp(&b.to_lowercase());
@@ -121,33 +175,58 @@ fn fill_tags(hash: &HashMap<String, Value>, filename: &str, cue: Option<&CueInfo
         }
     }
 
+    return tags;
+}
+
+fn fill_tags(tags: &HashMap<String, String>, filename: &str, cue: Option<&CueInfo>) -> MetaTags {
+    static RX_TRACK: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^(\d+)/(\d+)$").unwrap());
+
+    let file_path = Path::new(filename).canonicalize().unwrap();
+    let dir_path = file_path.parent().unwrap();
+
     let mut meta_tags = MetaTags {
-        title: first_val(&tags, &["title"]),
-        album: first_val(&tags, &["album"]),
-        artist: first_val(&tags, &["albumartist", "artist", "artists"]),
-        catalog_number: first_val(&tags, &["catalog", "catalognumber"]),
-        author: first_val(&tags, &["author"]),
-        comment: first_val(&tags, &["comment"]),
-        composer: first_val(&tags, &["composer"]),
-        lyricist: first_val(&tags, &["lyricist"]),
-        songwriter: first_val(&tags, &["songwriter"]),
-        date: first_val(&tags, &["date", "originaldate", "originalreleasedate"]),
-        disc: first_val(&tags, &["disc"]),
-        discs: first_val(&tags, &["disctotal", "totaldiscs"]),
-        disc_id: first_val(&tags, &["discid"]),
-        track: first_val(&tags, &["track"]),
-        tracks: first_val(&tags, &["tracktotal", "totaltracks"]),
-        genre: first_val(&tags, &["genre"]),
-        label: first_val(&tags, &["label"]),
-        performer: first_val(&tags, &["performer"]),
-        publisher: first_val(&tags, &["publisher"]),
-        year: first_val(&tags, &["year"]),
+        title: first_val(tags, &["title"]),
+        album: first_val(tags, &["album"]),
+        artist: first_val(tags, &["albumartist", "artist", "artists"]),
+        catalog_number: first_val(tags, &["catalog", "catalognumber"]),
+        isrc: first_val(tags, &["isrc", "tsrc"]),
+        author: first_val(tags, &["author"]),
+        comment: first_val(tags, &["comment"]),
+        composer: first_val(tags, &["composer"]),
+        lyricist: first_val(tags, &["lyricist"]),
+        songwriter: first_val(tags, &["songwriter"]),
+        date: first_val(tags, &["date", "originaldate", "originalreleasedate"]),
+        disc: first_val(tags, &["disc"]),
+        discs: first_val(tags, &["disctotal", "totaldiscs"]),
+        disc_id: first_val(tags, &["discid"]),
+        track: first_val(tags, &["track"]),
+        tracks: first_val(tags, &["tracktotal", "totaltracks"]),
+        genre: first_val(tags, &["genre"]),
+        label: first_val(tags, &["label"]),
+        performer: first_val(tags, &["performer"]),
+        publisher: first_val(tags, &["publisher"]),
+        year: first_val(tags, &["year"]),
         file_name: to_str(file_path.file_name()),
         dir_name: to_str(dir_path.file_name()),
         file_base: to_str(file_path.file_stem()),
         file_ext: to_str(file_path.extension()),
+        ..Default::default()
     };
 
+    for val in [
+        &mut meta_tags.artist,
+        &mut meta_tags.author,
+        &mut meta_tags.composer,
+        &mut meta_tags.lyricist,
+        &mut meta_tags.songwriter,
+        &mut meta_tags.genre,
+        &mut meta_tags.label,
+        &mut meta_tags.performer,
+        &mut meta_tags.publisher,
+    ] {
+        *val = split_values(val);
+    }
+
     if let Some(cue) = cue {
         if !cue.album.is_empty() {
             meta_tags.album.clone_from(&cue.album);
@@ -156,14 +235,26 @@ fn fill_tags(hash: &HashMap<String, Value>, filename: &str, cue: Option<&CueInfo
             meta_tags.title.clone_from(&cue.title);
         }
         if !cue.songwriter.is_empty() {
-            meta_tags.songwriter.clone_from(&cue.songwriter);
+            meta_tags.songwriter = split_values(&cue.songwriter);
+        }
+        if !cue.composer.is_empty() {
+            meta_tags.composer = split_values(&cue.composer);
+        }
+        if !cue.comment.is_empty() {
+            meta_tags.comment.clone_from(&cue.comment);
+        }
+        if !cue.isrc.is_empty() {
+            meta_tags.isrc.clone_from(&cue.isrc);
+        }
+        if !cue.catalog.is_empty() {
+            meta_tags.catalog_number.clone_from(&cue.catalog);
         }
         if !cue.genre.is_empty() {
-            meta_tags.genre.clone_from(&cue.genre);
+            meta_tags.genre = split_values(&cue.genre);
         }
         if !cue.performer.is_empty() {
-            meta_tags.performer.clone_from(&cue.performer);
-            meta_tags.artist.clone_from(&cue.performer);
+            meta_tags.performer = split_values(&cue.performer);
+            meta_tags.artist.clone_from(&meta_tags.performer);
         }
         if !cue.date.is_empty() {
             meta_tags.date.clone_from(&cue.date);
@@ -198,14 +289,20 @@ fn fill_tags(hash: &HashMap<String, Value>, filename: &str, cue: Option<&CueInfo
 }
 
 fn filesafe_str(s: &str) -> String {
-    return sanitize_with_options(
-        s,
-        Options {
-            replacement: "",
-            windows: false,
-            truncate: true,
-        },
-    );
+    return s
+        .split(VALUES_SEPARATOR)
+        .map(|val| {
+            sanitize_with_options(
+                val,
+                Options {
+                    replacement: "",
+                    windows: false,
+                    truncate: true,
+                },
+            )
+        })
+        .collect::<Vec<String>>()
+        .join(&VALUES_SEPARATOR.to_string());
 }
 
 pub fn sanitize_tags(meta: &MetaTags) -> MetaTags {
@@ -214,6 +311,7 @@ pub fn sanitize_tags(meta: &MetaTags) -> MetaTags {
         album: filesafe_str(&meta.album),
         artist: filesafe_str(&meta.artist),
         catalog_number: filesafe_str(&meta.catalog_number),
+        isrc: filesafe_str(&meta.isrc),
         author: filesafe_str(&meta.author),
         comment: filesafe_str(&meta.comment),
         composer: filesafe_str(&meta.composer),
@@ -234,6 +332,8 @@ pub fn sanitize_tags(meta: &MetaTags) -> MetaTags {
         dir_name: filesafe_str(&meta.dir_name),
         file_base: filesafe_str(&meta.file_base),
         file_ext: filesafe_str(&meta.file_ext),
+        is_compilation: filesafe_str(&meta.is_compilation),
+        album_artist_or_va: filesafe_str(&meta.album_artist_or_va),
     };
 }
 
@@ -265,6 +365,10 @@ pub fn prepare_filename_tags(meta_tags: &MetaTags, min_track_number_digits: u8)
         }
     }
 
+    if meta_tags.album_artist_or_va.is_empty() {
+        meta_tags.album_artist_or_va = meta_tags.artist.clone();
+    }
+
     if meta_tags.author.is_empty() && !meta_tags.artist.is_empty() {
         meta_tags.author = meta_tags.artist.clone();
     }
@@ -308,11 +412,48 @@ pub fn prepare_filename_tags(meta_tags: &MetaTags, min_track_number_digits: u8)
     return meta_tags;
 }
 
-pub fn extract_meta(
+// Only the duration of the probed file is known,
+// so an open-ended segment of any other file makes the duration unknown.
+fn track_duration(
     filename: &str,
+    file_duration: Option<&str>,
     cue: Option<&CueInfo>,
-    ffprobe_bin: &str,
-) -> Result<FileMeta, Box<dyn Error>> {
+) -> Option<f64> {
+    let file_duration = file_duration.and_then(|d| d.parse::<f64>().ok());
+    if let Some(cue) = cue {
+        let mut total = 0.0;
+        for segment in &cue.segments {
+            total += match segment.duration() {
+                Some(duration) => duration,
+                None if segment.filename == filename => file_duration? - segment.start(),
+                None => return None,
+            };
+        }
+        return Some(total);
+    }
+    return file_duration;
+}
+
+// the stream timestamps of lossless formats are usually in samples
+fn total_samples(
+    stream: &MetaStream,
+    file_duration: Option<&str>,
+    sample_rate: u32,
+) -> Option<u64> {
+    if let Some(duration_ts) = stream.duration_ts
+        && stream.time_base.as_deref() == Some(format!("1/{sample_rate}").as_str())
+    {
+        return Some(duration_ts);
+    }
+
+    let duration = stream.duration.as_deref().or(file_duration)?;
+    let duration = duration.parse::<f64>().ok()?;
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    let samples = (duration * f64::from(sample_rate)).round() as u64;
+    return Some(samples);
+}
+
+pub fn probe_file(filename: &str, ffprobe_bin: &str) -> Result<Meta, Box<dyn Error>> {
     let out = Command::new(ffprobe_bin)
         .args([
             "-v",
@@ -327,19 +468,66 @@ pub fn extract_meta(
         .stdout;
     let out = std::str::from_utf8(&out)?;
     let meta: Meta = serde_json::from_str(out)?;
+    return Ok(meta);
+}
+
+pub fn probed_duration(meta: &Meta) -> Option<f64> {
+    return meta.format.duration.as_deref()?.parse().ok();
+}
+
+// a CUESHEET Vorbis comment or a Cuesheet APEv2 tag
+pub fn embedded_cue_sheet(meta: &Meta) -> Option<String> {
+    let format_tags = meta.format.tags.as_ref()?;
+    let format_tags = normalize_tags(format_tags);
+    return format_tags
+        .get("cuesheet")
+        .filter(|cue_sheet| !cue_sheet.is_empty())
+        .cloned();
+}
 
-    let format_tags = meta.format.tags.unwrap_or_default();
+pub fn extract_meta(meta: &Meta, filename: &str, cue: Option<&CueInfo>) -> FileMeta {
+    let format_tags = meta.format.tags.clone().unwrap_or_default();
+    let format_tags = normalize_tags(&format_tags);
     let tags = fill_tags(&format_tags, filename, cue);
 
+    let mut track_artist = first_val(&format_tags, &["artist", "artists", "albumartist"]);
+    if let Some(cue) = cue
+        && !cue.performer.is_empty()
+    {
+        track_artist.clone_from(&cue.performer);
+    }
+
     let mut fmeta = FileMeta {
         tags,
+        album_artist: first_val(&format_tags, &["albumartist"]),
+        track_artist,
+        compilation: is_true_val(&first_val(
+            &format_tags,
+            &["compilation", "itunescompilation", "cpil"],
+        )),
+        duration: track_duration(filename, meta.format.duration.as_deref(), cue),
+        replaygain: ReplayGain::from_tags(&format_tags, cue.is_some()),
         ..Default::default()
     };
 
-    for s in meta.streams {
+    for s in &meta.streams {
+        if s.codec_type == MetaStream::AUDIO && fmeta.sample_rate.is_none() {
+            fmeta.sample_rate = s.sample_rate.as_ref().and_then(|rate| rate.parse().ok());
+            fmeta.channels = s.channels;
+            fmeta.total_samples = fmeta
+                .sample_rate
+                .and_then(|rate| total_samples(s, meta.format.duration.as_deref(), rate));
+            fmeta.codec = s.codec_name.clone().unwrap_or_default();
+            fmeta.bit_rate = s
+                .bit_rate
+                .as_ref()
+                .or(meta.format.bit_rate.as_ref())
+                .and_then(|rate| rate.parse().ok());
+        }
+
         if s.codec_type == MetaStream::VIDEO
-            && let Some(tags) = s.tags
-            && let Some(comment) = tags.comment
+            && let Some(tags) = &s.tags
+            && let Some(comment) = &tags.comment
             && comment == MetaStreamTags::COVER
             && let Some(w) = s.width
             && let Some(h) = s.height
@@ -350,5 +538,5 @@ pub fn extract_meta(
         }
     }
 
-    return Ok(fmeta);
+    return fmeta;
 }
@@ -0,0 +1,144 @@
+// SPDX-License-Identifier: GPL-3.0-only
+// 🄯 2026, Alexey Parfenov <zxed@alkatrazstudio.net>
+
+use crate::args::AppArgs;
+use sha2::{Digest, Sha256};
+use std::error::Error;
+use std::fs::{File, Metadata};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime};
+
+const CACHE_EXT: &str = "jpg";
+const TMP_EXT: &str = "tmp";
+const STALE_TMP_AGE: Duration = Duration::from_hours(1);
+
+// the cache is not used in dry-run mode, because nothing is converted
+pub fn cache_dir(app_args: &AppArgs) -> Option<&Path> {
+    if app_args.dry_run {
+        return None;
+    }
+    return app_args.pic_cache_dir.as_deref().map(Path::new);
+}
+
+// The converted image depends on the source image and the conversion parameters.
+pub fn cache_key(source: &[u8], app_args: &AppArgs) -> String {
+    let params = format!(
+        "{}x{}:{}",
+        app_args.max_pic_width, app_args.max_pic_height, app_args.pic_quality
+    );
+    let mut hasher = Sha256::new();
+    hasher.update(source);
+    hasher.update(params.as_bytes());
+    return format!("{:x}", hasher.finalize());
+}
+
+fn cache_path(dir: &Path, key: &str) -> PathBuf {
+    return dir.join(key).with_extension(CACHE_EXT);
+}
+
+// The modification time of a cached image is updated on each use,
+// so that the least recently used images are evicted first.
+pub fn load(dir: &Path, key: &str) -> Option<Vec<u8>> {
+    let path = cache_path(dir, key);
+    let data = std::fs::read(&path).ok()?;
+    if data.is_empty() {
+        return None;
+    }
+    if let Ok(file) = File::options().write(true).open(&path) {
+        file.set_modified(SystemTime::now()).ok();
+    }
+    return Some(data);
+}
+
+// The image is written under a temporary name first,
+// so that other processes never read a partially written image.
+// The name is unique per call, because the same image may be stored by several threads.
+pub fn store(dir: &Path, key: &str, data: &[u8]) -> Result<(), Box<dyn Error>> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    std::fs::create_dir_all(dir)?;
+    let path = cache_path(dir, key);
+    let tmp_path = dir.join(format!(
+        "{key}.{}-{}.{TMP_EXT}",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    std::fs::write(&tmp_path, data)?;
+    if let Err(e) = std::fs::rename(&tmp_path, &path) {
+        std::fs::remove_file(&tmp_path).ok();
+        return Err(e.into());
+    }
+    return Ok(());
+}
+
+// Another process may remove the same file at the same time.
+fn not_found_as_none<T>(result: std::io::Result<T>) -> std::io::Result<Option<T>> {
+    return match result {
+        Ok(val) => Ok(Some(val)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    };
+}
+
+// Removes the temporary files left by the interrupted runs.
+// The recent ones may still be written by other processes.
+fn remove_stale_tmp(path: &Path, meta: &Metadata) -> std::io::Result<()> {
+    let is_stale = meta
+        .modified()?
+        .elapsed()
+        .is_ok_and(|age| age > STALE_TMP_AGE);
+    if is_stale {
+        not_found_as_none(std::fs::remove_file(path))?;
+    }
+    return Ok(());
+}
+
+// Removes the least recently used images until the cache fits into PIC_CACHE_SIZE.
+// Returns the number of removed images.
+pub fn evict(app_args: &AppArgs) -> Result<usize, Box<dyn Error>> {
+    let Some(dir) = cache_dir(app_args) else {
+        return Ok(0);
+    };
+    if !dir.exists() {
+        return Ok(0);
+    }
+
+    let mut entries = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let Some(ext) = path.extension() else {
+            continue;
+        };
+        if ext != CACHE_EXT && ext != TMP_EXT {
+            continue;
+        }
+        let Some(meta) = not_found_as_none(std::fs::metadata(&path))? else {
+            continue;
+        };
+        if !meta.is_file() {
+            continue;
+        }
+        if ext == TMP_EXT {
+            remove_stale_tmp(&path, &meta)?;
+        } else {
+            entries.push((meta.modified()?, meta.len(), path));
+        }
+    }
+
+    let max_size = app_args.pic_cache_size.saturating_mul(1024 * 1024);
+    let mut size = entries.iter().map(|(_, len, _)| len).sum::<u64>();
+    entries.sort();
+
+    let mut removed = 0;
+    for (_, len, path) in entries {
+        if size <= max_size {
+            break;
+        }
+        if not_found_as_none(std::fs::remove_file(&path))?.is_some() {
+            removed += 1;
+        }
+        size -= len;
+    }
+    return Ok(removed);
+}
@@ -3,57 +3,91 @@
 
 use crate::args::AppArgs;
 use crate::concurrent_map::ConcurrentMap;
-use crate::convert::Progs;
+use crate::convert::{Item, OutputTrack, Progs};
 use crate::formats::Format;
+use crate::pic_cache;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 
 pub type PicsMap = ConcurrentMap<String, Option<Vec<u8>>>;
 
+// the cover art is always encoded as JPEG, the same way as for MP3
 pub fn ffmpeg_conv_pic_args(app_args: &AppArgs) -> Vec<String> {
     let fmt = format!(
         "scale='w=min({},iw)':h='min({},ih)':force_original_aspect_ratio=decrease:flags=lanczos",
         app_args.max_pic_width, app_args.max_pic_height
     );
     let mut conv_args = vec!["-vf".to_string(), fmt];
-    let quality_args = app_args
-        .output_ext_type
-        .pic_quality_args(app_args.pic_quality);
+    let quality_args = Format::MP3.pic_quality_args(app_args.pic_quality);
     conv_args.extend(quality_args);
     return conv_args;
 }
 
-fn conv_pic(pic_file: &str, app_args: &AppArgs, progs: &Progs) -> Option<Vec<u8>> {
-    match app_args.output_ext_type {
-        Format::Ogg => return std::fs::read(pic_file).ok(),
+// Converts the picture to a resized JPEG.
+fn conv_to_jpeg(
+    input_args: &[&str],
+    name: &str,
+    app_args: &AppArgs,
+    progs: &Progs,
+) -> Option<Vec<u8>> {
+    let pic_args = ffmpeg_conv_pic_args(app_args);
+    let pic_args = pic_args.iter().map(String::as_str).collect::<Vec<&str>>();
 
-        Format::MP3 => {
-            let pic_args = ffmpeg_conv_pic_args(app_args);
-            let pic_args = pic_args.iter().map(String::as_str).collect::<Vec<&str>>();
+    let mut args = input_args.to_vec();
+    args.extend(["-f", "mjpeg"]);
+    args.extend(pic_args);
+    args.push("-");
 
-            let mut args = vec!["-i", pic_file, "-f", "mjpeg"];
+    let args_str = shell_words::join(&args);
+    println!("PIC {}: {} {}", name, &progs.ffmpeg_bin, args_str);
 
-            args.extend(pic_args);
-            args.push("-");
+    if app_args.dry_run {
+        return Some(Vec::new());
+    }
 
-            let args_str = shell_words::join(&args);
-            println!("PIC {}: {} {}", pic_file, &progs.ffmpeg_bin, args_str);
+    let output = Command::new(&progs.ffmpeg_bin).args(args).output().ok()?;
+    if output.status.code()? != 0 {
+        println!(
+            "PIC {}: {}",
+            name,
+            std::str::from_utf8(&output.stderr).unwrap()
+        );
+        return None;
+    }
+    return Some(output.stdout);
+}
 
-            if app_args.dry_run {
-                return Some(Vec::new());
-            }
+fn conv_pic(pic_file: &str, app_args: &AppArgs, progs: &Progs) -> Option<Vec<u8>> {
+    let Some(cache_dir) = pic_cache::cache_dir(app_args) else {
+        return conv_to_jpeg(&["-i", pic_file], pic_file, app_args, progs);
+    };
 
-            let output = Command::new(&progs.ffmpeg_bin).args(args).output().ok()?;
-            if output.status.code()? != 0 {
-                println!(
-                    "PIC {}: {}",
-                    pic_file,
-                    std::str::from_utf8(&output.stderr).unwrap()
-                );
-                return None;
-            }
-            return Some(output.stdout);
-        }
+    let source = std::fs::read(pic_file).ok()?;
+    let key = pic_cache::cache_key(&source, app_args);
+    if let Some(data) = pic_cache::load(cache_dir, &key) {
+        println!("PIC {pic_file}: cached as {key}");
+        return Some(data);
+    }
+
+    let data = conv_to_jpeg(&["-i", pic_file], pic_file, app_args, progs)?;
+    if !data.is_empty()
+        && let Err(e) = pic_cache::store(cache_dir, &key, &data)
+    {
+        println!("PIC {pic_file}: can't cache: {e}");
     }
+    return Some(data);
+}
+
+// Extracts the cover art embedded into the audio file.
+fn extract_pic(filename: &str, app_args: &AppArgs, progs: &Progs) -> Option<Vec<u8>> {
+    return conv_to_jpeg(
+        &["-i", filename, "-an", "-map", "0:v:0"],
+        filename,
+        app_args,
+        progs,
+    );
 }
 
 impl PicsMap {
@@ -70,6 +104,21 @@ impl PicsMap {
         }
         return None;
     }
+
+    // the key is the audio file, so the tracks of one cue-sheet image share the picture
+    pub fn extract_pic_if_needed(
+        &self,
+        filename: &str,
+        args: &AppArgs,
+        progs: &Progs,
+    ) -> Option<Vec<u8>> {
+        if let Some(Some(p)) =
+            self.set_if_not_exists(&filename.into(), || extract_pic(filename, args, progs))
+        {
+            return Some(p);
+        }
+        return None;
+    }
 }
 
 pub fn find_cover_in_dir(
@@ -107,3 +156,157 @@ pub fn find_cover_in_dir(
 
     return None;
 }
+
+// The external cover art for the audio file.
+pub fn find_cover(
+    input_filename: &str,
+    app_args: &AppArgs,
+) -> Result<Option<String>, Box<dyn Error>> {
+    let canonical_dir = Path::new(input_filename)
+        .parent()
+        .ok_or_else(|| format!("no parent for {input_filename}"))?
+        .canonicalize()?;
+    let input_dir = canonical_dir
+        .to_str()
+        .ok_or("Can't get a string from the canonical path")?;
+
+    let mut cover_names = app_args.cover_names.clone();
+    if app_args.cover_name_from_dirname {
+        let dirname = canonical_dir
+            .file_name()
+            .ok_or("Can't get a dirname from the canonical path")?
+            .to_str()
+            .ok_or("Can't convert a dirname to string")?
+            .to_lowercase()
+            .trim()
+            .to_string();
+        cover_names.insert(0, dirname);
+    }
+    if app_args.cover_name_from_filename {
+        let basename = Path::new(input_filename)
+            .file_stem()
+            .ok_or("Can't get a file basename from the canonical path")?
+            .to_str()
+            .ok_or("Can't convert a file basename to string")?
+            .to_lowercase()
+            .trim()
+            .to_string();
+        cover_names.insert(0, basename);
+    }
+
+    return Ok(find_cover_in_dir(
+        input_dir,
+        &cover_names,
+        &app_args.cover_exts,
+    ));
+}
+
+#[derive(PartialEq, Eq)]
+enum CoverSource {
+    // the picture embedded into the audio file
+    Embedded(String),
+    File(String),
+}
+
+impl CoverSource {
+    fn conv(&self, pics: &PicsMap, app_args: &AppArgs, progs: &Progs) -> Option<Vec<u8>> {
+        return match self {
+            Self::Embedded(filename) => pics.extract_pic_if_needed(filename, app_args, progs),
+            Self::File(filename) => pics.conv_pic_if_needed(filename, app_args, progs),
+        };
+    }
+
+    fn filename(&self) -> &str {
+        return match self {
+            Self::Embedded(filename) | Self::File(filename) => filename,
+        };
+    }
+}
+
+// Writes the cover art of the converted tracks as COVER_FILE into their output directories.
+// If the tracks of one directory have different cover art, only the first one is written,
+// and the other ones are reported as errors.
+// The pictures already converted for the tracks are reused.
+// Returns the filenames of the written covers and the errors.
+pub fn export_covers(
+    tracks: &[OutputTrack],
+    items: &[Item],
+    pics: &PicsMap,
+    app_args: &AppArgs,
+    progs: &Progs,
+) -> (Vec<String>, Vec<String>) {
+    let mut filenames = Vec::new();
+    let mut errs = Vec::new();
+    let Some(cover_file) = &app_args.cover_file else {
+        return (filenames, errs);
+    };
+
+    let mut dirs: BTreeMap<PathBuf, Vec<CoverSource>> = BTreeMap::new();
+    for track in tracks {
+        let item = &items[track.index];
+        let source = if track.meta.has_pic && app_args.use_embed_pic {
+            Some(CoverSource::Embedded(item.filename.clone()))
+        } else {
+            match find_cover(&item.filename, app_args) {
+                Ok(filename) => filename.map(CoverSource::File),
+                Err(e) => {
+                    errs.push(format!("{}: {}", &item.filename, e));
+                    None
+                }
+            }
+        };
+
+        if let Some(dir) = Path::new(track.filename).parent()
+            && let Some(source) = source
+        {
+            let sources = dirs.entry(dir.to_path_buf()).or_default();
+            if !sources.contains(&source) {
+                sources.push(source);
+            }
+        }
+    }
+
+    for (dir, sources) in dirs {
+        let path = dir.join(cover_file);
+        let path_str = path.to_string_lossy().to_string();
+
+        // the sources are compared by the content of the converted pictures
+        let mut cover: Option<(&CoverSource, Vec<u8>)> = None;
+        for source in &sources {
+            let data = source.conv(pics, app_args, progs);
+            match (&cover, data) {
+                (_, None) => errs.push(format!("can't convert: {}", source.filename())),
+                (None, Some(data)) => cover = Some((source, data)),
+                (Some((cover_source, cover_data)), Some(data)) => {
+                    if data != *cover_data {
+                        errs.push(format!(
+                            "{}: not written to {}, it already has a different cover from {}",
+                            source.filename(),
+                            &path_str,
+                            cover_source.filename()
+                        ));
+                    }
+                }
+            }
+        }
+
+        let Some((source, data)) = cover else {
+            continue;
+        };
+        println!("[COVER] {} -> {}", source.filename(), &path_str);
+        if !app_args.overwrite && path.exists() {
+            errs.push(format!("file exists: {path_str}"));
+            continue;
+        }
+        if !app_args.dry_run {
+            let result = std::fs::create_dir_all(&dir).and_then(|()| std::fs::write(&path, data));
+            if let Err(e) = result {
+                errs.push(format!("{path_str}: {e}"));
+                continue;
+            }
+        }
+        filenames.push(path_str);
+    }
+
+    return (filenames, errs);
+}
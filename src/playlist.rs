@@ -0,0 +1,351 @@
+// SPDX-License-Identifier: GPL-3.0-only
+// 🄯 2026, Alexey Parfenov <zxed@alkatrazstudio.net>
+
+use crate::args::AppArgs;
+use crate::convert::{Item, OutputTrack, render_template, sanitize_filename};
+use crate::cue::read_string_from_file;
+use crate::files::canonical;
+use crate::meta::{FileMeta, join_values, prepare_filename_tags};
+use encoding_rs::UTF_8;
+use lexical_sort::natural_lexical_only_alnum_cmp;
+use path_dedot::ParseDot;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::path::{Path, PathBuf};
+
+pub struct SourcePlaylist {
+    pub path: PathBuf,
+    // relative to the input directory
+    pub rel_path: PathBuf,
+}
+
+pub fn is_playlist(path: &Path) -> bool {
+    return path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("m3u") || ext.eq_ignore_ascii_case("m3u8"));
+}
+
+// %XX sequences of a file:// URL are the bytes of UTF-8
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%'
+            && let Some(byte) = s
+                .get(i + 1..i + 3)
+                .filter(|hex| hex.bytes().all(|c| c.is_ascii_hexdigit()))
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+        {
+            decoded.push(byte);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    return String::from_utf8_lossy(&decoded).to_string();
+}
+
+// An entry of M3U may be a path relative to the playlist or a URL.
+// Only the local files are returned.
+fn entry_path(playlist_dir: &Path, entry: &str) -> Option<PathBuf> {
+    let entry = entry.trim();
+    if entry.is_empty() || entry.starts_with('#') {
+        return None;
+    }
+    let entry = if let Some(url_path) = entry.strip_prefix("file://") {
+        let url_path = url_path.strip_prefix("localhost").unwrap_or(url_path);
+        percent_decode(url_path)
+    } else if entry.contains("://") {
+        return None;
+    } else {
+        entry.to_string()
+    };
+    let path = playlist_dir.join(entry.replace('\\', "/"));
+    return Some(
+        path.parse_dot()
+            .map_or_else(|_| path.clone(), |path| path.to_path_buf()),
+    );
+}
+
+// The encoding of *.m3u is detected just as for cue sheets,
+// *.m3u8 is always UTF-8.
+pub fn read_playlist(path: &Path) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let is_utf8 = path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("m3u8"));
+    let text = read_string_from_file(path, is_utf8.then_some(UTF_8))?.text;
+    let playlist_dir = path.parent().unwrap_or_else(|| Path::new(""));
+    let paths = text
+        .lines()
+        .filter_map(|line| entry_path(playlist_dir, line))
+        .collect();
+    return Ok(paths);
+}
+
+fn tag_number(s: &str) -> u32 {
+    return s
+        .split('/')
+        .next()
+        .and_then(|n| n.trim().parse().ok())
+        .unwrap_or_default();
+}
+
+// The tracks of every directory are ordered by their disc and track numbers,
+// and the directories are naturally sorted.
+fn cmp_tracks(a: &OutputTrack, b: &OutputTrack) -> Ordering {
+    let dir = |track: &OutputTrack| {
+        return Path::new(track.filename)
+            .parent()
+            .and_then(|dir| dir.to_str())
+            .unwrap_or_default()
+            .to_string();
+    };
+    let key = |track: &OutputTrack| {
+        return (
+            tag_number(&track.meta.tags.disc),
+            tag_number(&track.meta.tags.track),
+            track.index,
+        );
+    };
+    return natural_lexical_only_alnum_cmp(&dir(a), &dir(b)).then_with(|| key(a).cmp(&key(b)));
+}
+
+fn relative_path(dir: &Path, path: &Path) -> String {
+    let dir_components = dir.components().collect::<Vec<_>>();
+    let components = path.components().collect::<Vec<_>>();
+    let common = dir_components
+        .iter()
+        .zip(&components)
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let mut parts = vec!["..".to_string(); dir_components.len() - common];
+    parts.extend(
+        components[common..]
+            .iter()
+            .map(|component| component.as_os_str().to_string_lossy().to_string()),
+    );
+    return parts.join("/");
+}
+
+fn extinf(meta: &FileMeta, values_separator: &str) -> String {
+    #[allow(clippy::cast_possible_truncation)]
+    let duration = meta
+        .duration
+        .filter(|&duration| duration >= 0.0)
+        .map_or(-1, |duration| duration.round() as i64);
+    let artist = join_values(&meta.tags.artist, values_separator);
+    let title = if artist.is_empty() {
+        meta.tags.title.clone()
+    } else {
+        format!("{artist} - {}", meta.tags.title)
+    };
+    return format!("#EXTINF:{duration},{title}");
+}
+
+// Every track is added to the playlists rendered from its tags by PLAYLIST_TEMPLATE.
+// Returns the filenames of the playlists and the errors.
+pub fn write_playlists(tracks: &[OutputTrack], app_args: &AppArgs) -> (Vec<String>, Vec<String>) {
+    let mut filenames = Vec::new();
+    let mut errs = Vec::new();
+    let mut playlists: BTreeMap<PathBuf, Vec<&OutputTrack>> = BTreeMap::new();
+    for template in &app_args.playlist_templates {
+        for track in tracks {
+            let tags = prepare_filename_tags(&track.meta.tags, app_args.min_track_number_digits);
+            let filename = render_template(template, &tags, &app_args.values_separator)
+                .and_then(|filename| sanitize_filename(&(filename + ".m3u8")));
+            match filename {
+                Ok(filename) => playlists
+                    .entry(Path::new(&app_args.output_dir).join(filename))
+                    .or_default()
+                    .push(track),
+                Err(e) => errs.push(format!("{}: playlist: {e}", track.filename)),
+            }
+        }
+    }
+
+    for (path, mut tracks) in playlists {
+        tracks.sort_by(|a, b| cmp_tracks(a, b));
+        tracks.dedup_by_key(|track| track.index);
+        match write_playlist(&path, &tracks, app_args) {
+            Ok(filename) => filenames.push(filename),
+            Err(e) => errs.push(format!("{}: {e}", path.display())),
+        }
+    }
+
+    return (filenames, errs);
+}
+
+pub struct RewrittenPlaylists {
+    pub filenames: Vec<String>,
+    // the entries that can't be mapped to the converted files
+    pub unmapped: Vec<String>,
+    pub errs: Vec<String>,
+}
+
+// Writes the playlists of the input directories into OUTPUT_DIR,
+// replacing every source file with the files converted from it.
+// A cue sheet or a cue-sheet image is replaced with all its tracks.
+// A playlist is not written if its output file is already taken
+// by another playlist, including the ones in `written`.
+pub fn rewrite_playlists(
+    playlists: &[SourcePlaylist],
+    tracks: &[OutputTrack],
+    items: &[Item],
+    written: &[String],
+    app_args: &AppArgs,
+) -> RewrittenPlaylists {
+    let mut sources: HashMap<PathBuf, Vec<&OutputTrack>> = HashMap::new();
+    for track in tracks {
+        let item = &items[track.index];
+        let mut source_files = vec![canonical(Path::new(&item.filename))];
+        if let Some(cue) = &item.cue {
+            source_files.push(canonical(Path::new(&cue.cue_filename)));
+            source_files.extend(
+                cue.segments
+                    .iter()
+                    .map(|segment| canonical(Path::new(&segment.filename))),
+            );
+        }
+        source_files.sort();
+        source_files.dedup();
+        for source_file in source_files {
+            sources.entry(source_file).or_default().push(track);
+        }
+    }
+
+    let mut result = RewrittenPlaylists {
+        filenames: Vec::new(),
+        unmapped: Vec::new(),
+        errs: Vec::new(),
+    };
+    let mut outputs: HashMap<PathBuf, String> = written
+        .iter()
+        .map(|filename| (PathBuf::from(filename), filename.clone()))
+        .collect();
+    for playlist in playlists {
+        let playlist_str = playlist.path.to_string_lossy().to_string();
+        let path = Path::new(&app_args.output_dir).join(playlist.rel_path.with_extension("m3u8"));
+        if let Some(other) = outputs.get(&path) {
+            result.errs.push(format!(
+                "{playlist_str}: resolves to {} just as {other}",
+                path.display()
+            ));
+            continue;
+        }
+        outputs.insert(path.clone(), playlist_str.clone());
+
+        let entries = match read_playlist(&playlist.path) {
+            Ok(entries) => entries,
+            Err(e) => {
+                result.errs.push(format!("{playlist_str}: {e}"));
+                continue;
+            }
+        };
+
+        let mut playlist_tracks = Vec::new();
+        for entry in entries {
+            if let Some(entry_tracks) = sources.get(&canonical(&entry)) {
+                playlist_tracks.extend(entry_tracks);
+            } else {
+                result
+                    .unmapped
+                    .push(format!("{playlist_str}: {}", entry.display()));
+            }
+        }
+
+        match write_playlist(&path, &playlist_tracks, app_args) {
+            Ok(filename) => result.filenames.push(filename),
+            Err(e) => result.errs.push(format!("{playlist_str}: {e}")),
+        }
+    }
+
+    return result;
+}
+
+fn write_playlist(
+    path: &Path,
+    tracks: &[&OutputTrack],
+    app_args: &AppArgs,
+) -> Result<String, Box<dyn Error>> {
+    let path_str = path.to_str().ok_or("Can't convert path to string")?;
+    let dir_path = path
+        .parent()
+        .ok_or_else(|| format!("no parent for {path_str}"))?;
+    if !app_args.overwrite && path.exists() {
+        return Err(format!("file exists: {path_str}").into());
+    }
+
+    let mut lines = str_vec!["#EXTM3U"];
+    for track in tracks {
+        lines.push(extinf(track.meta, &app_args.values_separator));
+        lines.push(relative_path(dir_path, Path::new(track.filename)));
+    }
+
+    if !app_args.dry_run {
+        std::fs::create_dir_all(dir_path)?;
+        std::fs::write(path, lines.join("\n") + "\n")?;
+    }
+    return Ok(path_str.to_string());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percent_decode_escapes() {
+        assert_eq!(percent_decode("a%20b%C3%A9"), "a bé");
+        assert_eq!(percent_decode("%2fx%2F"), "/x/");
+    }
+
+    #[test]
+    fn percent_decode_malformed() {
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%2"), "%2");
+        assert_eq!(percent_decode("%zz%"), "%zz%");
+        assert_eq!(percent_decode("%+1"), "%+1");
+        assert_eq!(percent_decode("%FF"), "\u{FFFD}");
+        assert_eq!(percent_decode("é%"), "é%");
+    }
+
+    #[test]
+    fn entry_paths() {
+        let dir = Path::new("/music/lists");
+        assert_eq!(
+            entry_path(dir, "file:///music/a%20b.flac"),
+            Some(PathBuf::from("/music/a b.flac"))
+        );
+        assert_eq!(
+            entry_path(dir, "file://localhost/music/a.flac"),
+            Some(PathBuf::from("/music/a.flac"))
+        );
+        assert_eq!(
+            entry_path(dir, " ..\\Album\\01.flac "),
+            Some(PathBuf::from("/music/Album/01.flac"))
+        );
+        assert_eq!(entry_path(dir, "http://example.com/a.mp3"), None);
+        assert_eq!(entry_path(dir, "#EXTINF:10,a"), None);
+        assert_eq!(entry_path(dir, ""), None);
+    }
+
+    #[test]
+    fn relative_paths() {
+        let dir = Path::new("/out/lists");
+        assert_eq!(relative_path(dir, Path::new("/out/lists/a.mp3")), "a.mp3");
+        assert_eq!(relative_path(dir, Path::new("/out/A/a.mp3")), "../A/a.mp3");
+        assert_eq!(
+            relative_path(dir, Path::new("/other/a.mp3")),
+            "../../other/a.mp3"
+        );
+        assert_eq!(
+            relative_path(Path::new("lists"), Path::new("music/a.mp3")),
+            "../music/a.mp3"
+        );
+        assert_eq!(relative_path(Path::new(""), Path::new("a.mp3")), "a.mp3");
+    }
+}
@@ -0,0 +1,98 @@
+// SPDX-License-Identifier: GPL-3.0-only
+// 🄯 2026, Alexey Parfenov <zxed@alkatrazstudio.net>
+
+use crate::args::AppArgs;
+use crate::loudness::{Loudness, output_loudness};
+use crate::meta::FileMeta;
+use std::collections::HashMap;
+
+// ReplayGain 2.0 reference level in LUFS
+const REFERENCE_LOUDNESS: f64 = -18.0;
+
+#[derive(PartialEq, Eq)]
+pub enum ReplayGainMode {
+    Off,
+    Track,
+    Album,
+}
+
+#[derive(Default, Clone)]
+pub struct ReplayGain {
+    pub track_gain: String,
+    pub track_peak: String,
+    pub album_gain: String,
+    pub album_peak: String,
+}
+
+impl ReplayGain {
+    pub fn from_tags(tags: &HashMap<String, String>, is_cue: bool) -> Self {
+        let val = |key: &str| tags.get(key).cloned().unwrap_or_default();
+
+        // the track values of a cue-sheet image belong to the whole image
+        if is_cue {
+            return Self {
+                album_gain: val("replaygainalbumgain"),
+                album_peak: val("replaygainalbumpeak"),
+                ..Default::default()
+            };
+        }
+
+        return Self {
+            track_gain: val("replaygaintrackgain"),
+            track_peak: val("replaygaintrackpeak"),
+            album_gain: val("replaygainalbumgain"),
+            album_peak: val("replaygainalbumpeak"),
+        };
+    }
+
+    pub const fn tags(&self) -> [(&'static str, &String); 4] {
+        return [
+            ("REPLAYGAIN_TRACK_GAIN", &self.track_gain),
+            ("REPLAYGAIN_TRACK_PEAK", &self.track_peak),
+            ("REPLAYGAIN_ALBUM_GAIN", &self.album_gain),
+            ("REPLAYGAIN_ALBUM_PEAK", &self.album_peak),
+        ];
+    }
+}
+
+fn gain_str(loudness: &Loudness) -> Option<String> {
+    let gain = REFERENCE_LOUDNESS - loudness.integrated;
+    if !gain.is_finite() {
+        return None;
+    }
+    return Some(format!("{gain:.2} dB"));
+}
+
+fn peak_str(loudness: &Loudness) -> Option<String> {
+    let peak = 10_f64.powf(loudness.true_peak / 20.0);
+    if !peak.is_finite() {
+        return None;
+    }
+    return Some(format!("{peak:.6}"));
+}
+
+pub fn apply_replaygain(metas: &mut [Result<FileMeta, String>], app_args: &AppArgs) {
+    if app_args.replaygain == ReplayGainMode::Off {
+        return;
+    }
+
+    for meta in metas.iter_mut().flatten() {
+        let (track, album) = output_loudness(meta, app_args);
+
+        if let Some(track) = track {
+            meta.replaygain.track_gain = gain_str(&track).unwrap_or_default();
+            meta.replaygain.track_peak = peak_str(&track).unwrap_or_default();
+        }
+
+        if app_args.replaygain == ReplayGainMode::Album
+            && let Some(album) = album
+        {
+            meta.replaygain.album_gain = gain_str(&album).unwrap_or_default();
+            meta.replaygain.album_peak = peak_str(&album).unwrap_or_default();
+        } else {
+            // the existing album values may be calculated by a different algorithm
+            meta.replaygain.album_gain.clear();
+            meta.replaygain.album_peak.clear();
+        }
+    }
+}
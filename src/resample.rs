@@ -0,0 +1,57 @@
+// SPDX-License-Identifier: GPL-3.0-only
+// 🄯 2026, Alexey Parfenov <zxed@alkatrazstudio.net>
+
+use crate::args::AppArgs;
+use crate::meta::FileMeta;
+
+const DEFAULT_SAMPLE_RATE: u32 = 44100;
+
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub enum LimitMode {
+    Exact,
+    Cap,
+}
+
+fn limit(source: Option<u32>, target: Option<u32>, mode: LimitMode) -> Option<u32> {
+    return match (target, mode) {
+        (None, _) => source,
+        (Some(target), LimitMode::Exact) => Some(target),
+        (Some(target), LimitMode::Cap) => Some(source.map_or(target, |source| source.min(target))),
+    };
+}
+
+pub fn output_sample_rate(meta: &FileMeta, app_args: &AppArgs) -> Option<u32> {
+    return limit(
+        meta.sample_rate,
+        app_args.sample_rate,
+        app_args.sample_rate_mode,
+    );
+}
+
+pub fn output_channels(meta: &FileMeta, app_args: &AppArgs) -> Option<u32> {
+    return limit(meta.channels, app_args.channels, app_args.channels_mode);
+}
+
+pub fn resample_filters(meta: &FileMeta, app_args: &AppArgs, force: bool) -> Vec<String> {
+    let sample_rate = output_sample_rate(meta, app_args);
+    if !force && sample_rate == meta.sample_rate {
+        return Vec::new();
+    }
+
+    let sample_rate = sample_rate.unwrap_or(DEFAULT_SAMPLE_RATE);
+    let mut params = vec![format!("osr={sample_rate}")];
+    if let Some(dither) = &app_args.dither {
+        params.push(format!("dither_method={dither}"));
+    }
+    return vec![format!("aresample={}", params.join(":"))];
+}
+
+pub fn channel_args(meta: &FileMeta, app_args: &AppArgs) -> Vec<String> {
+    let channels = output_channels(meta, app_args);
+    if let Some(channels) = channels
+        && channels != meta.channels.unwrap_or_default()
+    {
+        return str_vec!["-ac", channels];
+    }
+    return Vec::new();
+}
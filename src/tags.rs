@@ -0,0 +1,179 @@
+// SPDX-License-Identifier: GPL-3.0-only
+// 🄯 2026, Alexey Parfenov <zxed@alkatrazstudio.net>
+
+use crate::formats::Format;
+use lofty::TextEncoding;
+use lofty::config::{ParseOptions, WriteOptions};
+use lofty::file::AudioFile;
+use lofty::id3::v2::{Frame, FrameId, TextInformationFrame};
+use lofty::mpeg::MpegFile;
+use lofty::ogg::{OggPictureStorage, VorbisFile};
+use lofty::picture::{Picture, PictureType};
+use std::borrow::Cow;
+use std::error::Error;
+use std::fs::File;
+use std::io::{Seek, SeekFrom};
+
+pub struct TagValues {
+    pub name: &'static str,
+    pub values: Vec<String>,
+}
+
+fn id3v2_frame_id(name: &str) -> Option<&'static str> {
+    return match name {
+        "artist" => Some("TPE1"),
+        "composer" => Some("TCOM"),
+        "genre" => Some("TCON"),
+        "lyricist" => Some("TEXT"),
+        "performer" => Some("TPE3"),
+        "publisher" => Some("TPUB"),
+        _ => None,
+    };
+}
+
+fn open_rw(filename: &str) -> Result<File, Box<dyn Error>> {
+    let file = File::options().read(true).write(true).open(filename)?;
+    return Ok(file);
+}
+
+// ffmpeg can only write a single value per tag,
+// so multi-value tags are rewritten after the file is encoded:
+// ID3v2.4 text frames get null-separated values,
+// the tags without a standard frame get null-separated TXXX frames,
+// and Vorbis comments get repeated fields.
+pub fn write_multi_values(
+    filename: &str,
+    format: &Format,
+    tags: &[TagValues],
+) -> Result<(), Box<dyn Error>> {
+    let mut file = open_rw(filename)?;
+
+    match format {
+        Format::MP3 => {
+            let mut mp3 = MpegFile::read_from(&mut file, ParseOptions::new())?;
+            let mut id3v2 = mp3.id3v2().cloned().unwrap_or_default();
+            for tag in tags {
+                let value = tag.values.join("\0");
+                // ffmpeg writes a TXXX frame for any tag without a standard frame,
+                // keeping the case of the tag name
+                id3v2.retain(|frame| {
+                    return !matches!(frame, Frame::UserText(frame)
+                        if frame.description.eq_ignore_ascii_case(tag.name));
+                });
+                if let Some(frame_id) = id3v2_frame_id(tag.name) {
+                    let frame = TextInformationFrame::new(
+                        FrameId::Valid(Cow::Borrowed(frame_id)),
+                        TextEncoding::UTF8,
+                        value,
+                    );
+                    id3v2.insert(Frame::Text(frame));
+                } else {
+                    id3v2.insert_user_text(tag.name.to_uppercase(), value);
+                }
+            }
+            mp3.set_id3v2(id3v2);
+            file.seek(SeekFrom::Start(0))?;
+            mp3.save_to(&mut file, WriteOptions::default())?;
+        }
+
+        Format::Ogg => {
+            let mut ogg = VorbisFile::read_from(&mut file, ParseOptions::new())?;
+            let comments = ogg.vorbis_comments_mut();
+            for tag in tags {
+                let key = tag.name.to_uppercase();
+                comments.remove(&key).for_each(drop);
+                for val in &tag.values {
+                    comments.push(key.clone(), val.clone());
+                }
+            }
+            file.seek(SeekFrom::Start(0))?;
+            ogg.save_to(&mut file, WriteOptions::default())?;
+        }
+    }
+
+    return Ok(());
+}
+
+// Vorbis comments store the cover art as a base64-encoded FLAC picture block
+// in the METADATA_BLOCK_PICTURE field, along with its MIME type and dimensions.
+pub fn write_ogg_cover(filename: &str, data: &[u8]) -> Result<(), Box<dyn Error>> {
+    let mut picture = Picture::from_reader(&mut &data[..])?;
+    picture.set_pic_type(PictureType::CoverFront);
+    picture.set_description(Some("Album cover".to_string()));
+
+    let mut file = open_rw(filename)?;
+    let mut ogg = VorbisFile::read_from(&mut file, ParseOptions::new())?;
+    let comments = ogg.vorbis_comments_mut();
+    comments.remove_picture_type(PictureType::CoverFront);
+    comments.insert_picture(picture, None)?;
+    file.seek(SeekFrom::Start(0))?;
+    ogg.save_to(&mut file, WriteOptions::default())?;
+
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lofty::id3::v2::Id3v2Tag;
+
+    // MPEG-1 Layer III, 128 kbps, 44100 Hz
+    fn write_silent_mp3(filename: &str) {
+        let mut frame = vec![0_u8; 417];
+        frame[..4].copy_from_slice(&[0xFF, 0xFB, 0x90, 0x64]);
+        std::fs::write(filename, frame.repeat(10)).unwrap();
+    }
+
+    fn read_id3v2(filename: &str) -> Id3v2Tag {
+        let mut file = File::open(filename).unwrap();
+        let mp3 = MpegFile::read_from(&mut file, ParseOptions::new()).unwrap();
+        return mp3.id3v2().cloned().unwrap();
+    }
+
+    #[test]
+    fn multi_values_replace_ffmpeg_frames() {
+        let path = std::env::temp_dir().join(format!("musbconv-tags-{}.mp3", std::process::id()));
+        let filename = path.to_str().unwrap();
+        write_silent_mp3(filename);
+
+        // the frames as written by ffmpeg
+        let mut file = open_rw(filename).unwrap();
+        let mut mp3 = MpegFile::read_from(&mut file, ParseOptions::new()).unwrap();
+        let mut id3v2 = Id3v2Tag::new();
+        id3v2.insert_user_text("author".to_string(), "A1; A2".to_string());
+        id3v2.insert_user_text("LABEL".to_string(), "L1; L2".to_string());
+        id3v2.insert_user_text("lyricist".to_string(), "W1; W2".to_string());
+        mp3.set_id3v2(id3v2);
+        file.seek(SeekFrom::Start(0)).unwrap();
+        mp3.save_to(&mut file, WriteOptions::default()).unwrap();
+        drop(file);
+
+        let tags = ["author", "label", "lyricist"].map(|name| TagValues {
+            name,
+            values: vec!["1".to_string(), "2".to_string()],
+        });
+        write_multi_values(filename, &Format::MP3, &tags).unwrap();
+        let id3v2 = read_id3v2(filename);
+        std::fs::remove_file(&path).unwrap();
+
+        let user_texts = |name: &str| {
+            return id3v2
+                .iter()
+                .filter(|frame| {
+                    matches!(frame, Frame::UserText(frame)
+                        if frame.description.eq_ignore_ascii_case(name))
+                })
+                .count();
+        };
+        assert_eq!(user_texts("author"), 1);
+        assert_eq!(user_texts("label"), 1);
+        assert_eq!(user_texts("lyricist"), 0);
+        assert_eq!(id3v2.get_user_text("AUTHOR"), Some("1\u{0}2"));
+        assert_eq!(
+            id3v2
+                .get_text(&FrameId::Valid(Cow::Borrowed("TEXT")))
+                .map(|text| text.split('\0').collect::<Vec<_>>()),
+            Some(vec!["1", "2"])
+        );
+    }
+}